@@ -0,0 +1,198 @@
+use std::cell::RefCell;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use serde_derive::Serialize;
+
+use crate::common::*;
+use crate::problem::{Problem, ProblemId};
+use crate::solver::{self, SOLVERS};
+use crate::storage::Storage;
+
+pub const DEFAULT_OUTPUT: &str = "bench.json";
+
+// A solver with its time budget, written as NAME or NAME:SECONDS.
+#[derive(Clone, Debug)]
+pub struct SolverSpec {
+    pub name: String,
+    pub budget: Duration,
+}
+
+impl SolverSpec {
+    pub fn parse(s: &str, default_budget: Duration) -> Result<Self> {
+        let (name, budget) = match s.split_once(':') {
+            Some((name, seconds)) => (name, Duration::from_secs_f64(seconds.parse()?)),
+            None => (s, default_budget),
+        };
+        if !SOLVERS.contains_key(name) {
+            return Err(anyhow::anyhow!("Unknown solver '{}'", name));
+        }
+        Ok(SolverSpec {
+            name: name.to_owned(),
+            budget,
+        })
+    }
+}
+
+pub struct BenchOptions {
+    pub specs: Vec<SolverSpec>,
+    // Problems to run on, all of them if None.
+    pub ids: Option<Vec<ProblemId>>,
+    // Runs of every solver on every problem, the run N uses the seed `seed + N`.
+    pub repeats: u32,
+    pub seed: u64,
+    pub output: PathBuf,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        BenchOptions {
+            specs: vec![],
+            ids: None,
+            repeats: 3,
+            seed: solver::SEED,
+            output: PathBuf::from(DEFAULT_OUTPUT),
+        }
+    }
+}
+
+// A single run of a solver on a problem, the times are in seconds from its start.
+#[derive(Serialize)]
+pub struct BenchRun {
+    pub problem: ProblemId,
+    pub solver: String,
+    pub budget: f64,
+    pub seed: u64,
+    pub first_valid: Option<f64>,
+    // Time and dislikes of every improvement of the best valid pose.
+    pub trace: Vec<(f64, u64)>,
+    // Best valid dislikes at the end of the run.
+    pub dislikes: Option<u64>,
+    pub elapsed: f64,
+    // Whether the solver finished before the budget ran out.
+    pub finished: bool,
+}
+
+// Runs the solvers one after another so that the timings are comparable. The budget is checked
+// between the poses the solver yields, so the ones yielding rarely can overrun it. Nothing is
+// saved to the storage.
+pub fn run(storage: &Storage, options: &BenchOptions) -> Result<Vec<BenchRun>> {
+    let ids = match &options.ids {
+        Some(ids) => ids.clone(),
+        None => storage.list_problems()?,
+    };
+    let mut runs = Vec::new();
+    for id in &ids {
+        let problem = storage.load_problem(id)?;
+        for spec in &options.specs {
+            for repeat in 0..options.repeats {
+                let seed = options.seed + repeat as u64;
+                let run = solver::with_seed(seed, || run_once(storage, &problem, spec, seed));
+                info!(
+                    "Problem {}, {} with seed {}: dislikes = {:?}, took {:.3}s",
+                    id, spec.name, seed, run.dislikes, run.elapsed
+                );
+                runs.push(run);
+            }
+        }
+    }
+    let mut file = std::fs::File::create(&options.output)?;
+    serde_json::to_writer_pretty(&mut file, &runs)?;
+    Ok(runs)
+}
+
+fn run_once(storage: &Storage, problem: &Problem, spec: &SolverSpec, seed: u64) -> BenchRun {
+    let solver = SOLVERS.get(&spec.name).unwrap();
+    let pose = problem.figure.get_default_pose();
+    let start = Instant::now();
+    let mut gen = solver.solve_gen(problem.clone(), Rc::new(RefCell::new(pose)), storage);
+    let mut run = BenchRun {
+        problem: problem.id.clone(),
+        solver: spec.name.clone(),
+        budget: spec.budget.as_secs_f64(),
+        seed,
+        first_valid: None,
+        trace: vec![],
+        dislikes: None,
+        elapsed: 0.0,
+        finished: false,
+    };
+    loop {
+        let pose = match gen.resume() {
+            Some(pose) => pose,
+            None => {
+                run.finished = true;
+                break;
+            }
+        };
+        let elapsed = start.elapsed().as_secs_f64();
+        let pose = pose.borrow();
+        if problem.validate(&pose) {
+            let dislikes = problem.dislikes(&pose);
+            run.first_valid.get_or_insert(elapsed);
+            if run.dislikes.map(|d| dislikes < d).unwrap_or(true) {
+                run.dislikes = Some(dislikes);
+                run.trace.push((elapsed, dislikes));
+            }
+        }
+        if start.elapsed() > spec.budget {
+            break;
+        }
+    }
+    run.elapsed = start.elapsed().as_secs_f64();
+    run
+}
+
+pub fn print_report(options: &BenchOptions, runs: &[BenchRun]) {
+    let seconds = |t: Option<f64>| match t {
+        Some(t) => format!("{:.3}", t),
+        None => "-".to_owned(),
+    };
+    println!(
+        "{:>7}  {:<20}  {:>5}  {:>10}  {:>8}  {:>8}  {:>8}",
+        "Problem", "Solver", "Valid", "1st valid", "Best", "Median", "Time"
+    );
+    let mut ids = runs.iter().map(|r| &r.problem).collect::<Vec<_>>();
+    ids.dedup();
+    for id in ids {
+        for spec in &options.specs {
+            let runs = runs
+                .iter()
+                .filter(|r| r.problem == *id && r.solver == spec.name)
+                .collect::<Vec<_>>();
+            let mut first_valid = runs
+                .iter()
+                .filter_map(|r| r.first_valid)
+                .collect::<Vec<_>>();
+            first_valid.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let mut dislikes = runs.iter().filter_map(|r| r.dislikes).collect::<Vec<_>>();
+            dislikes.sort_unstable();
+            let elapsed = runs.iter().map(|r| r.elapsed).sum::<f64>() / runs.len() as f64;
+            println!(
+                "{:>7}  {:<20}  {:>5}  {:>10}  {:>8}  {:>8}  {:>8.3}",
+                id,
+                spec.name,
+                format!("{}/{}", dislikes.len(), runs.len()),
+                seconds(median(&first_valid)),
+                dislikes
+                    .first()
+                    .map(|d| d.to_string())
+                    .unwrap_or_else(|| "-".to_owned()),
+                median(&dislikes)
+                    .map(|d| d.to_string())
+                    .unwrap_or_else(|| "-".to_owned()),
+                elapsed
+            );
+        }
+    }
+    println!(
+        "Median time to the first valid pose and dislikes over the valid runs, mean time of a run. \
+         All runs are in {}",
+        options.output.display()
+    );
+}
+
+fn median<T: Copy>(sorted: &[T]) -> Option<T> {
+    sorted.get(sorted.len() / 2).copied()
+}
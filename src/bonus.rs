@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::common::*;
+use crate::problem::{BonusType, Point, Problem, ProblemId};
+use crate::storage::Storage;
+
+// A bonus collected on `source` by covering `position` with a vertex, usable on `target`.
+pub struct BonusEdge {
+    pub source: ProblemId,
+    pub target: ProblemId,
+    pub bonus: BonusType,
+    pub position: Point,
+    // Stored valid solutions of the source covering the position with their dislikes, "best"
+    // for the best solution and the solver names for the rest.
+    pub unlocked_by: Vec<(String, u64)>,
+}
+
+// Bonuses between all the problems with the current state of the stored solutions.
+pub struct BonusGraph {
+    pub edges: Vec<BonusEdge>,
+    // Dislikes of the best valid solution of every problem.
+    problems: HashMap<ProblemId, (Option<u64>, Problem)>,
+}
+
+// Bonus picked for a target: net gain, the edge and the cheapest solution unlocking it.
+type Choice<'a> = (f64, &'a BonusEdge, Option<(String, f64)>);
+
+// Bonuses usable on every target: the source problem, the bonus and the position to cover there.
+type BonusIndex = HashMap<ProblemId, Vec<(ProblemId, BonusType, Point)>>;
+
+lazy_static! {
+    // Indices by problems folder, the problems don't change while we run.
+    static ref INDICES: Mutex<HashMap<PathBuf, Arc<BonusIndex>>> = Default::default();
+}
+
+fn bonus_index(storage: &Storage) -> Result<Arc<BonusIndex>> {
+    let mut indices = INDICES.lock().unwrap();
+    if let Some(index) = indices.get(storage.problems_path()) {
+        return Ok(index.clone());
+    }
+    let mut index = BonusIndex::new();
+    for id in storage.list_problems()? {
+        for b in storage.load_problem(&id)?.bonuses {
+            index
+                .entry(b.problem)
+                .or_default()
+                .push((id.clone(), b.bonus, b.position));
+        }
+    }
+    let index = Arc::new(index);
+    indices.insert(storage.problems_path().to_owned(), index.clone());
+    Ok(index)
+}
+
+// Problem whose best solution collects the bonus for `target`, None if no problem unlocks it yet.
+// Only the solutions of the sources are loaded, they change while we run.
+pub fn bonus_source(
+    storage: &Storage,
+    target: &ProblemId,
+    bonus: BonusType,
+) -> Result<Option<ProblemId>> {
+    let index = bonus_index(storage)?;
+    for (source, b, position) in index.get(target).into_iter().flatten() {
+        if *b != bonus {
+            continue;
+        }
+        let unlocked = storage
+            .load_solution(source)?
+            .map(|s| s.state.valid && s.pose.vertices.contains(position))
+            .unwrap_or(false);
+        if unlocked {
+            return Ok(Some(source.clone()));
+        }
+    }
+    Ok(None)
+}
+
+impl BonusGraph {
+    pub fn build(storage: &Storage) -> Result<Self> {
+        let subfolders = storage.list_subfolders()?;
+        let mut edges = Vec::new();
+        let mut problems = HashMap::new();
+        for id in storage.list_problems()? {
+            let problem = storage.load_problem(&id)?;
+            let best = storage.load_solution(&id)?.filter(|s| s.state.valid);
+            let best_dislikes = best.as_ref().map(|s| s.state.dislikes);
+
+            // All the valid stored poses of the problem.
+            let mut poses = Vec::new();
+            if let Some(s) = best {
+                poses.push(("best".to_owned(), s.state.dislikes, s.pose));
+            }
+            for name in &subfolders {
+                let state = storage.load_state(&id, Some(name))?;
+                let pose = storage.load_pose(&id, Some(name))?;
+                if let (Some(state), Some(pose)) = (state, pose) {
+                    if state.valid {
+                        poses.push((name.clone(), state.dislikes, pose));
+                    }
+                }
+            }
+
+            for b in &problem.bonuses {
+                edges.push(BonusEdge {
+                    source: id.clone(),
+                    target: b.problem.clone(),
+                    bonus: b.bonus,
+                    position: b.position,
+                    unlocked_by: poses
+                        .iter()
+                        .filter(|(_, _, pose)| pose.vertices.contains(&b.position))
+                        .map(|(name, dislikes, _)| (name.clone(), *dislikes))
+                        .collect(),
+                });
+            }
+            problems.insert(id, (best_dislikes, problem));
+        }
+        Ok(BonusGraph { edges, problems })
+    }
+
+    // Estimated score of the best solution, assuming someone has zero dislikes.
+    fn score(&self, id: &ProblemId) -> f64 {
+        match self.problems.get(id) {
+            Some((Some(dislikes), problem)) => problem.score(*dislikes, 0) as f64,
+            _ => 0.0,
+        }
+    }
+
+    fn score_with(&self, id: &ProblemId, dislikes: u64) -> f64 {
+        match self.problems.get(id) {
+            Some((_, problem)) => problem.score(dislikes, 0) as f64,
+            None => 0.0,
+        }
+    }
+
+    // Score to gain on the target if the bonus makes it optimal, minus the score lost on the
+    // source by switching to a solution that unlocks it. None if it's not unlocked yet.
+    fn net_gain(&self, edge: &BonusEdge) -> (f64, Option<(String, f64)>) {
+        let gain = self.score_with(&edge.target, 0) - self.score(&edge.target);
+        let cheapest = edge
+            .unlocked_by
+            .iter()
+            .map(|(name, dislikes)| {
+                let cost =
+                    (self.score(&edge.source) - self.score_with(&edge.source, *dislikes)).max(0.0);
+                (name.clone(), cost)
+            })
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+        (gain, cheapest)
+    }
+
+    pub fn print_report(&self) {
+        println!("Bonuses:");
+        for edge in &self.edges {
+            let unlocked = if edge.unlocked_by.is_empty() {
+                "locked".to_owned()
+            } else {
+                format!(
+                    "unlocked by {}",
+                    edge.unlocked_by
+                        .iter()
+                        .map(|(name, _)| &name[..])
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            };
+            println!(
+                "  {:>3} -> {:>3}  {:<11} at ({}, {}): {}",
+                edge.source,
+                edge.target,
+                String::from(edge.bonus),
+                edge.position.x,
+                edge.position.y,
+                unlocked
+            );
+        }
+
+        // Only one bonus can be used per pose, so pick the best one for every target.
+        let mut plan: HashMap<&ProblemId, Choice> = HashMap::new();
+        for edge in &self.edges {
+            let (gain, cheapest) = self.net_gain(edge);
+            let net = gain - cheapest.as_ref().map(|(_, cost)| *cost).unwrap_or(0.0);
+            let better = match plan.get(&edge.target) {
+                None => true,
+                // Unlocked ones first, then by the net gain.
+                Some((best_net, _, best_cheapest)) => {
+                    (cheapest.is_some(), net) > (best_cheapest.is_some(), *best_net)
+                }
+            };
+            if better {
+                plan.insert(&edge.target, (net, edge, cheapest));
+            }
+        }
+        let mut plan = plan.into_iter().collect::<Vec<_>>();
+        plan.sort_by(|a, b| (b.1).0.partial_cmp(&(a.1).0).unwrap());
+
+        println!("Plan (estimated gain if the bonus makes the target optimal):");
+        for (target, (net, edge, cheapest)) in plan {
+            if net <= 0.0 {
+                continue;
+            }
+            let action = match cheapest {
+                Some((name, cost)) if name == "best" || cost == 0.0 => {
+                    format!("unlocked by {}", name)
+                }
+                Some((name, cost)) => format!(
+                    "switch problem {} to the {} solution (-{:.0})",
+                    edge.source, name, cost
+                ),
+                None => format!("collect on problem {} first", edge.source),
+            };
+            println!(
+                "  {:>3}: +{:.0} with {} from problem {}, {}",
+                target,
+                net,
+                String::from(edge.bonus),
+                edge.source,
+                action
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bonus_source_skips_locked_sources() {
+        let (storage, _solutions) = crate::mock_portal::storage();
+        let index = bonus_index(&storage).unwrap();
+        let (target, bonuses) = index.iter().next().expect("some problem has a bonus");
+        // No solutions are stored, so none of the bonuses is collected yet.
+        for (_, bonus, _) in bonuses {
+            assert_eq!(bonus_source(&storage, target, *bonus).unwrap(), None);
+        }
+    }
+}
@@ -2,6 +2,14 @@ pub use log::{debug, error, info, trace, warn};
 
 pub type Result<T> = std::result::Result<T, anyhow::Error>;
 
+// Seconds since the Unix epoch.
+pub fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
 pub trait PointConversion<To> {
     fn convert(&self) -> To;
 }
@@ -0,0 +1,61 @@
+use crate::common::*;
+use crate::portal::SESSION;
+use crate::problem::{Problem, ProblemId};
+use crate::storage::Storage;
+
+// Downloads the problems from `from` to `to` into the problems folder, or until the portal has
+// no more problems if there's no `to`. Unchanged files are left alone.
+pub fn download_all(storage: &Storage, from: u32, to: Option<u32>) -> Result<()> {
+    let (mut added, mut updated, mut unchanged, mut new_bonuses) = (0, 0, 0, 0);
+    let mut id = from;
+    while to.map(|to| id <= to).unwrap_or(true) {
+        let data = match SESSION.fetch_problem(id as u64)? {
+            Some(data) => data,
+            None if to.is_none() => break,
+            None => {
+                warn!("Problem {} does not exist", id);
+                id += 1;
+                continue;
+            }
+        };
+        let problem_id = ProblemId::from(id);
+        let problem = Problem::from_json(problem_id.clone(), data.as_bytes())?;
+        let old = storage.problem_json(&problem_id).ok();
+        if old.as_ref() == Some(&data) {
+            unchanged += 1;
+            id += 1;
+            continue;
+        }
+
+        let old = old.and_then(|old| Problem::from_json(problem_id.clone(), old.as_bytes()).ok());
+        match old {
+            Some(_) => updated += 1,
+            None => added += 1,
+        }
+        // All the bonuses of a new problem are new.
+        for b in &problem.bonuses {
+            let known = old
+                .iter()
+                .flat_map(|old| &old.bonuses)
+                .any(|o| o.bonus == b.bonus && o.problem == b.problem && o.position == b.position);
+            if !known {
+                new_bonuses += 1;
+                println!(
+                    "Problem {}: new {} bonus for problem {} at ({}, {})",
+                    id,
+                    String::from(b.bonus),
+                    b.problem,
+                    b.position.x,
+                    b.position.y
+                );
+            }
+        }
+        storage.save_problem_json(&problem_id, &data)?;
+        id += 1;
+    }
+    println!(
+        "Problems: {} new, {} updated, {} unchanged; {} new bonuses",
+        added, updated, unchanged, new_bonuses
+    );
+    Ok(())
+}
@@ -0,0 +1,93 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::common::*;
+use crate::problem::ProblemId;
+use crate::storage::Storage;
+
+// Best valid dislikes of every solver on every problem, from the history of the solutions.
+pub struct Leaderboard {
+    // Problem id to the solvers with their best valid dislikes, best first.
+    pub problems: BTreeMap<ProblemId, Vec<(String, u64)>>,
+}
+
+impl Leaderboard {
+    pub fn build(storage: &Storage) -> Result<Self> {
+        let mut problems = BTreeMap::new();
+        for id in storage.list_problems()? {
+            let mut best: HashMap<String, u64> = HashMap::new();
+            for entry in storage.load_history(&id)? {
+                if !entry.state.valid {
+                    continue;
+                }
+                let dislikes = best.entry(entry.solver).or_insert(u64::MAX);
+                *dislikes = (*dislikes).min(entry.state.dislikes);
+            }
+            let mut solvers = best.into_iter().collect::<Vec<_>>();
+            solvers.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+            problems.insert(id, solvers);
+        }
+        Ok(Leaderboard { problems })
+    }
+
+    // Solvers with the best dislikes on the problem, several on a tie.
+    pub fn winners(&self, id: &ProblemId) -> Vec<&str> {
+        match self.problems.get(id) {
+            Some(solvers) if !solvers.is_empty() => solvers
+                .iter()
+                .take_while(|(_, dislikes)| *dislikes == solvers[0].1)
+                .map(|(name, _)| &name[..])
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    pub fn print(&self) {
+        println!(
+            "{:>7}  {:>8}  {:<30}  Runner-up",
+            "Problem", "Dislikes", "Winners"
+        );
+        let mut wins: HashMap<&str, usize> = HashMap::new();
+        let mut unsolved = Vec::new();
+        for (id, solvers) in &self.problems {
+            let winners = self.winners(id);
+            if winners.is_empty() {
+                unsolved.push(id);
+                continue;
+            }
+            for &name in &winners {
+                *wins.entry(name).or_default() += 1;
+            }
+            let runner_up = match solvers.get(winners.len()) {
+                Some((name, dislikes)) => format!("{} ({})", name, dislikes),
+                None => "-".to_owned(),
+            };
+            println!(
+                "{:>7}  {:>8}  {:<30}  {}",
+                id,
+                solvers[0].1,
+                winners.join(", "),
+                runner_up
+            );
+        }
+
+        let mut wins = wins.into_iter().collect::<Vec<_>>();
+        wins.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        println!("Wins per solver (the ties count for every winner):");
+        for (name, count) in wins {
+            println!("  {:<20}  {}", name, count);
+        }
+        if unsolved.is_empty() {
+            println!("Every problem has a valid solution");
+        } else {
+            println!(
+                "No valid solution for {} problems: {}",
+                unsolved.len(),
+                unsolved
+                    .iter()
+                    .map(|id| id.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+    }
+}
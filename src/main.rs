@@ -9,16 +9,28 @@ extern crate generator;
 #[macro_use]
 extern crate lazy_static;
 
+mod bench;
+mod bonus;
 mod common;
+mod download;
+mod leaderboard;
+#[cfg(test)]
+mod mock_portal;
 mod portal;
 mod problem;
 mod render;
 mod runner;
+mod sat;
+mod scheduler;
+mod score;
 mod solver;
+mod stats;
 mod storage;
 mod transform;
+mod upload;
 
 use crate::common::*;
+use crate::problem::{HistoryEntry, ProblemId, Solution, SolutionState};
 
 fn main() -> Result<()> {
     let app = App::new("icfpc2021")
@@ -28,6 +40,22 @@ fn main() -> Result<()> {
                 .takes_value(false)
                 .multiple_occurrences(true),
         )
+        .arg(
+            Arg::new("PROBLEMS")
+                .long("problems")
+                .takes_value(true)
+                .env("PROBLEMS_DIR")
+                .default_value(storage::DEFAULT_PROBLEMS_PATH)
+                .global(true),
+        )
+        .arg(
+            Arg::new("SOLUTIONS")
+                .long("solutions")
+                .takes_value(true)
+                .env("SOLUTIONS_DIR")
+                .default_value(storage::DEFAULT_SOLUTIONS_PATH)
+                .global(true),
+        )
         // Run one or all solvers on one or all problems
         .subcommand(
             App::new("solve")
@@ -37,7 +65,66 @@ fn main() -> Result<()> {
                         .takes_value(true)
                         .default_missing_value(""),
                 )
-                .arg(Arg::new("ID").short('i').takes_value(true)),
+                .arg(Arg::new("ID").short('i').takes_value(true))
+                .arg(
+                    Arg::new("START")
+                        .long("start")
+                        .takes_value(true)
+                        .about("Initial pose: default, best, a solver name or a path"),
+                )
+                .arg(
+                    Arg::new("BONUS")
+                        .long("bonus")
+                        .takes_value(true)
+                        .requires("ID")
+                        .about("Collect the bonus this problem has for the given one"),
+                ),
+        )
+        // Run the solvers on the problems with the largest expected gain first, in rounds with
+        // growing budgets
+        .subcommand(
+            App::new("schedule")
+                .arg(
+                    Arg::new("SOLVERS")
+                        .short('a')
+                        .takes_value(true)
+                        .default_value("tree_search")
+                        .about("Solvers to run, like tree_search,annealing"),
+                )
+                .arg(
+                    Arg::new("IDS")
+                        .long("ids")
+                        .takes_value(true)
+                        .about("Problems to run on, like 1,5,10-20"),
+                )
+                .arg(
+                    Arg::new("START")
+                        .long("start")
+                        .takes_value(true)
+                        .about("Initial pose: default, best, a solver name or a path"),
+                )
+                .arg(Arg::new("BEST").long("best").takes_value(true).about(
+                    "File with the best dislikes among all the teams, '<id> <dislikes>' per line",
+                ))
+                .arg(
+                    Arg::new("BUDGET")
+                        .long("budget")
+                        .takes_value(true)
+                        .about("Seconds per job in the first round"),
+                )
+                .arg(
+                    Arg::new("GROWTH")
+                        .long("growth")
+                        .takes_value(true)
+                        .about("How many times the budget grows every round"),
+                )
+                .arg(Arg::new("ROUNDS").long("rounds").takes_value(true))
+                .arg(
+                    Arg::new("HOURS")
+                        .long("hours")
+                        .takes_value(true)
+                        .about("Stop starting new jobs after that many hours"),
+                ),
         )
         .subcommand(
             App::new("render")
@@ -50,8 +137,176 @@ fn main() -> Result<()> {
                 .arg("<ID> problem N")
                 .arg("<PATH> path/to/N.problem"),
         )
-        .subcommand(App::new("upload_all"))
-        .subcommand(App::new("stats"));
+        // Download the problems into the problems folder, until the last one if there's no --to
+        .subcommand(
+            App::new("download_all")
+                .arg(
+                    Arg::new("FROM")
+                        .long("from")
+                        .takes_value(true)
+                        .default_value("1"),
+                )
+                .arg(Arg::new("TO").long("to").takes_value(true)),
+        )
+        // Encode a problem for external SAT/MaxSAT solvers
+        .subcommand(
+            App::new("export")
+                .arg(Arg::new("ID").short('i').takes_value(true).required(true))
+                .arg(
+                    Arg::new("FORMAT")
+                        .short('f')
+                        .takes_value(true)
+                        .possible_values(&["cnf", "wcnf"])
+                        .default_value("wcnf"),
+                )
+                .arg("<PATH> path/to/N.wcnf"),
+        )
+        // Read a pose from a SAT solver model (or the bundled DPLL) and store it
+        .subcommand(
+            App::new("import")
+                .arg(Arg::new("ID").short('i').takes_value(true).required(true))
+                .arg(Arg::new("DPLL").long("dpll").takes_value(false))
+                .arg(
+                    Arg::new("PATH")
+                        .about("path/to/model")
+                        .required_unless_present("DPLL"),
+                ),
+        )
+        // List every solution found for a problem
+        .subcommand(
+            App::new("history").arg(Arg::new("ID").short('i').takes_value(true).required(true)),
+        )
+        // Make an entry of the history the current best solution
+        .subcommand(
+            App::new("restore")
+                .arg(Arg::new("ID").short('i').takes_value(true).required(true))
+                .arg(
+                    Arg::new("ENTRY")
+                        .short('n')
+                        .takes_value(true)
+                        .required(true)
+                        .about("Entry number from the history command"),
+                ),
+        )
+        // Which solver has the best solution of every problem, according to the history
+        .subcommand(App::new("leaderboard"))
+        // Recompute the metadata of the stored solutions
+        .subcommand(
+            App::new("reindex").arg(
+                Arg::new("DRY_RUN")
+                    .long("dry-run")
+                    .takes_value(false)
+                    .about("Only report the discrepancies"),
+            ),
+        )
+        .subcommand(
+            App::new("upload_all")
+                .arg(
+                    Arg::new("DRY_RUN")
+                        .long("dry-run")
+                        .takes_value(false)
+                        .about("Only print what would be uploaded"),
+                )
+                .arg(
+                    Arg::new("IDS")
+                        .long("ids")
+                        .takes_value(true)
+                        .about("Problems to upload, like 1,5,10-20"),
+                )
+                .arg(
+                    Arg::new("MIN_IMPROVEMENT")
+                        .long("min-improvement")
+                        .takes_value(true)
+                        .about("Upload only if the dislikes go down by at least that much"),
+                )
+                .arg(
+                    Arg::new("FORCE")
+                        .long("force")
+                        .takes_value(false)
+                        .about("Upload even if the server has the same or a better score"),
+                )
+                .arg(
+                    Arg::new("INTERVAL")
+                        .long("interval")
+                        .takes_value(true)
+                        .about("Seconds between the submissions"),
+                ),
+        )
+        // Submit the solutions left in the outbox by upload_all
+        .subcommand(
+            App::new("flush")
+                .arg(
+                    Arg::new("ROUNDS")
+                        .long("rounds")
+                        .takes_value(true)
+                        .about("Attempts to submit the failed ones, with growing delays"),
+                )
+                .arg(
+                    Arg::new("INTERVAL")
+                        .long("interval")
+                        .takes_value(true)
+                        .about("Seconds between the submissions"),
+                ),
+        )
+        // Compare the solvers with fixed seeds and budgets, the stored solutions stay untouched
+        .subcommand(
+            App::new("bench")
+                .arg(
+                    Arg::new("SOLVERS")
+                        .short('a')
+                        .takes_value(true)
+                        .required(true)
+                        .about("Solvers to compare, like tree_search,annealing:30"),
+                )
+                .arg(
+                    Arg::new("IDS")
+                        .long("ids")
+                        .takes_value(true)
+                        .about("Problems to run on, like 1,5,10-20"),
+                )
+                .arg(
+                    Arg::new("BUDGET")
+                        .long("budget")
+                        .takes_value(true)
+                        .default_value("60")
+                        .about("Seconds per run for the solvers without their own"),
+                )
+                .arg(
+                    Arg::new("REPEATS")
+                        .short('n')
+                        .takes_value(true)
+                        .about("Runs of every solver on every problem"),
+                )
+                .arg(
+                    Arg::new("SEED")
+                        .long("seed")
+                        .takes_value(true)
+                        .about("Seed of the first run, the next ones add 1"),
+                )
+                .arg(
+                    Arg::new("OUTPUT")
+                        .short('o')
+                        .takes_value(true)
+                        .default_value(bench::DEFAULT_OUTPUT),
+                ),
+        )
+        // Which bonuses are unlocked and where to use them
+        .subcommand(App::new("bonuses"))
+        // Expected contest score and where it can still grow
+        .subcommand(App::new("score").arg(
+            Arg::new("BEST").long("best").takes_value(true).about(
+                "File with the best dislikes among all the teams, '<id> <dislikes>' per line",
+            ),
+        ))
+        .subcommand(
+            App::new("stats").arg(
+                Arg::new("FORMAT")
+                    .long("format")
+                    .takes_value(true)
+                    .possible_values(&["text", "json", "csv"])
+                    .default_value("text"),
+            ),
+        );
 
     let app_matches = app.get_matches();
 
@@ -69,6 +324,11 @@ fn main() -> Result<()> {
         ColorChoice::Auto,
     )?;
 
+    let storage = storage::Storage::new(
+        std::path::Path::new(app_matches.value_of("PROBLEMS").unwrap()),
+        std::path::Path::new(app_matches.value_of("SOLUTIONS").unwrap()),
+    )?;
+
     match app_matches.subcommand() {
         Some(("solve", matches)) => {
             let solver_name = match matches.value_of("SOLVER") {
@@ -83,7 +343,54 @@ fn main() -> Result<()> {
             let id = matches
                 .value_of("ID")
                 .map(|s| s.parse().expect("Failed to parse the problem ID"));
-            runner::run(solver_name, id)?;
+            let start = matches
+                .value_of("START")
+                .map(runner::StartPose::from)
+                .unwrap_or(runner::StartPose::Default);
+            let bonus = match matches.value_of("BONUS") {
+                Some(bonus) => Some(bonus.parse()?),
+                None => None,
+            };
+            runner::run(&storage, solver_name, id, &start, bonus)?;
+        }
+        Some(("schedule", matches)) => {
+            let mut options = scheduler::ScheduleOptions {
+                solvers: matches
+                    .value_of("SOLVERS")
+                    .unwrap()
+                    .split(',')
+                    .map(|s| s.trim().to_owned())
+                    .collect(),
+                ..Default::default()
+            };
+            for name in &options.solvers {
+                if !solver::SOLVERS.contains_key(name) {
+                    return Err(anyhow::anyhow!("Unknown solver '{}'", name));
+                }
+            }
+            if let Some(ids) = matches.value_of("IDS") {
+                options.ids = Some(upload::parse_ids(ids)?);
+            }
+            if let Some(start) = matches.value_of("START") {
+                options.start = start.into();
+            }
+            if let Some(path) = matches.value_of("BEST") {
+                options.best_dislikes = score::load_best_dislikes(std::path::Path::new(path))?;
+            }
+            if let Some(budget) = matches.value_of("BUDGET") {
+                options.budget = std::time::Duration::from_secs_f64(budget.parse()?);
+            }
+            if let Some(growth) = matches.value_of("GROWTH") {
+                options.budget_growth = growth.parse()?;
+            }
+            if let Some(rounds) = matches.value_of("ROUNDS") {
+                options.rounds = rounds.parse()?;
+            }
+            if let Some(hours) = matches.value_of("HOURS") {
+                let hours = std::time::Duration::from_secs_f64(hours.parse::<f64>()? * 3600.0);
+                options.deadline = Some(std::time::Instant::now() + hours);
+            }
+            scheduler::run(&storage, &options)?;
         }
         Some(("render", matches)) => {
             let solution_path = matches
@@ -93,7 +400,7 @@ fn main() -> Result<()> {
                 Some(i) => i
                     .parse()
                     .expect(&format!("Failed to parse problem ID '{}'", i)),
-                None => 1,
+                None => ProblemId::from(1),
             };
             let solver = match matches.value_of("SOLVER") {
                 Some(name) => solver::SOLVERS
@@ -101,7 +408,7 @@ fn main() -> Result<()> {
                     .expect(&format!("Failed to find solver '{}'", name)),
                 None => &solver::SOLVERS["id"],
             };
-            interact(solution_path, solver, id)?;
+            interact(&storage, solution_path, solver, &id)?;
         }
         Some(("download", matches)) => {
             portal::SESSION.download_problem(
@@ -109,53 +416,212 @@ fn main() -> Result<()> {
                 matches.value_of("PATH").unwrap(),
             )?;
         }
-        Some(("upload_all", _matches)) => {
-            for i in 1..=storage::get_problems_count() {
-                let solution = storage::load_solution(i)?;
-                if let Some(mut s) = solution {
-                    if !s.state.valid {
-                        warn!("For problem {} solution does not fit into the hole", i);
-                        continue;
-                    }
-                    if s.state.dislikes >= s.server_state.dislikes {
-                        info!(
-                            "For problem {} solution with same or higher score {} was already submitted (server has {})",
-                            i, s.state.dislikes, s.server_state.dislikes
-                        );
-                        continue;
-                    }
-
-                    warn!(
-                        "Uploading solution for problem {}, dislikes: {}",
-                        i, s.state.dislikes
+        Some(("download_all", matches)) => {
+            let from = matches.value_of("FROM").unwrap().parse()?;
+            let to = match matches.value_of("TO") {
+                Some(to) => Some(to.parse()?),
+                None => None,
+            };
+            download::download_all(&storage, from, to)?;
+        }
+        Some(("export", matches)) => {
+            let id: ProblemId = matches.value_of("ID").unwrap().parse()?;
+            let mut problem = storage.load_problem(&id)?;
+            let encoding = sat::Encoding::new(&mut problem);
+            let mut file =
+                std::io::BufWriter::new(std::fs::File::create(matches.value_of("PATH").unwrap())?);
+            match matches.value_of("FORMAT") {
+                Some("cnf") => encoding.write_cnf(&mut file)?,
+                _ => encoding.write_wcnf(&mut file)?,
+            }
+        }
+        Some(("import", matches)) => {
+            let id: ProblemId = matches.value_of("ID").unwrap().parse()?;
+            let mut problem = storage.load_problem(&id)?;
+            let encoding = sat::Encoding::new(&mut problem);
+            let assignment = match matches.value_of("PATH") {
+                Some(path) if !matches.is_present("DPLL") => {
+                    sat::parse_assignment(&std::fs::read_to_string(path)?)?
+                }
+                _ => encoding
+                    .solve_dpll()
+                    .ok_or_else(|| anyhow::anyhow!("Problem {} has no valid pose", id))?,
+            };
+            let pose = encoding.decode(&assignment)?;
+            let dislikes = problem.dislikes(&pose);
+            let solution = Solution {
+                id: id.clone(),
+                state: SolutionState {
+                    dislikes,
+                    valid: problem.validate(&pose),
+                    optimal: dislikes == 0,
+                },
+                pose,
+                server_state: storage.load_server_state(&id)?,
+            };
+            println!(
+                "Problem {}: dislikes = {}, valid = {}",
+                id, solution.state.dislikes, solution.state.valid
+            );
+            storage.append_history(
+                &id,
+                &HistoryEntry::new("sat", None, std::time::Duration::default(), &solution),
+            )?;
+            if solution.state.valid {
+                storage.solver_solutions_path("sat")?;
+                storage.save_solution(&solution, Some("sat"))?;
+                if let Some(best_dislikes) = storage.save_best_if_better(&solution)? {
+                    println!(
+                        "Replacing the current best solution ({} > {})",
+                        best_dislikes, solution.state.dislikes
                     );
-                    portal::SESSION.upload_solution(i as u64, &s.pose)?;
-                    s.server_state.dislikes = s.state.dislikes;
-                    storage::save_server_state(i, &s.server_state)?;
-                } else {
-                    info!("No solution for problem {}", i);
                 }
             }
         }
-        Some(("stats", _matches)) => {
-            // NOTE: we're assuming the files are named N.problem as this allows to iterate them in order
-            for i in 1..=storage::get_problems_count() {
-                let problem = storage::load_problem(i)?;
-                println!("Problem {}: ", i);
-                println!("  Hole: {} vertices", problem.hole.len());
+        Some(("history", matches)) => {
+            let id: ProblemId = matches.value_of("ID").unwrap().parse()?;
+            let history = storage.load_history(&id)?;
+            if history.is_empty() {
+                println!("No history for problem {}", id);
+            }
+            for (n, entry) in history.iter().enumerate() {
                 println!(
-                    "  Figure: {} vertices, {} edges, e={}, max_score={}",
-                    problem.figure.vertices.len(),
-                    problem.figure.edges.len(),
-                    problem.figure.epsilon,
-                    ((problem.figure.vertices.len()
-                        * problem.figure.edges.len()
-                        * problem.hole.len()) as f64)
-                        .log2() as u32
-                        * 1000
+                    "{:>4}  {}  {} (seed {}), took {}.{:03}s: dislikes = {}, valid = {}, bonuses = [{}]",
+                    n,
+                    entry.timestamp,
+                    entry.solver,
+                    entry
+                        .seed
+                        .map(|s| s.to_string())
+                        .unwrap_or_else(|| "-".to_owned()),
+                    entry.runtime_ms / 1000,
+                    entry.runtime_ms % 1000,
+                    entry.state.dislikes,
+                    entry.state.valid,
+                    entry
+                        .pose
+                        .bonuses
+                        .iter()
+                        .map(|b| format!("{}:{}", String::from(b.bonus), b.problem))
+                        .collect::<Vec<_>>()
+                        .join(", ")
                 );
             }
         }
+        Some(("restore", matches)) => {
+            let id: ProblemId = matches.value_of("ID").unwrap().parse()?;
+            let n: usize = matches.value_of("ENTRY").unwrap().parse()?;
+            let problem = storage.load_problem(&id)?;
+            let entry = storage
+                .load_history(&id)?
+                .into_iter()
+                .nth(n)
+                .ok_or_else(|| anyhow::anyhow!("Problem {} has no history entry {}", id, n))?;
+            // The validation may have changed since the entry was written.
+            let dislikes = problem.dislikes(&entry.pose);
+            let solution = Solution {
+                id: id.clone(),
+                state: SolutionState {
+                    dislikes,
+                    valid: problem.validate(&entry.pose),
+                    optimal: dislikes == 0,
+                },
+                pose: entry.pose,
+                server_state: storage.load_server_state(&id)?,
+            };
+            if !solution.state.valid {
+                return Err(anyhow::anyhow!(
+                    "History entry {} of problem {} is not a valid pose",
+                    n,
+                    id
+                ));
+            }
+            println!(
+                "Restoring entry {} by {} as the best solution of problem {}, dislikes = {}",
+                n, entry.solver, id, dislikes
+            );
+            storage.save_solution(&solution, None)?;
+        }
+        Some(("leaderboard", _matches)) => {
+            leaderboard::Leaderboard::build(&storage)?.print();
+        }
+        Some(("reindex", matches)) => {
+            let dry_run = matches.is_present("DRY_RUN");
+            let discrepancies = runner::reindex(&storage, dry_run)?;
+            println!(
+                "{} discrepancies {}",
+                discrepancies,
+                if dry_run { "found" } else { "fixed" }
+            );
+        }
+        Some(("upload_all", matches)) => {
+            let mut options = upload::UploadOptions {
+                dry_run: matches.is_present("DRY_RUN"),
+                force: matches.is_present("FORCE"),
+                ..Default::default()
+            };
+            if let Some(ids) = matches.value_of("IDS") {
+                options.ids = Some(upload::parse_ids(ids)?);
+            }
+            if let Some(min_improvement) = matches.value_of("MIN_IMPROVEMENT") {
+                options.min_improvement = min_improvement.parse()?;
+            }
+            if let Some(interval) = matches.value_of("INTERVAL") {
+                options.interval = std::time::Duration::from_secs_f64(interval.parse()?);
+            }
+            upload::upload_all(&storage, &portal::SESSION, &options)?;
+        }
+        Some(("flush", matches)) => {
+            let rounds = match matches.value_of("ROUNDS") {
+                Some(rounds) => rounds.parse()?,
+                None => upload::FLUSH_ROUNDS,
+            };
+            let interval = match matches.value_of("INTERVAL") {
+                Some(interval) => std::time::Duration::from_secs_f64(interval.parse()?),
+                None => upload::UploadOptions::default().interval,
+            };
+            let results = upload::flush(&storage, &portal::SESSION, interval, rounds, None)?;
+            upload::print_flush_results(&storage, &results)?;
+        }
+        Some(("bench", matches)) => {
+            let budget =
+                std::time::Duration::from_secs_f64(matches.value_of("BUDGET").unwrap().parse()?);
+            let mut options = bench::BenchOptions {
+                specs: matches
+                    .value_of("SOLVERS")
+                    .unwrap()
+                    .split(',')
+                    .map(|s| bench::SolverSpec::parse(s.trim(), budget))
+                    .collect::<Result<_>>()?,
+                output: matches.value_of("OUTPUT").unwrap().into(),
+                ..Default::default()
+            };
+            if let Some(ids) = matches.value_of("IDS") {
+                options.ids = Some(upload::parse_ids(ids)?);
+            }
+            if let Some(repeats) = matches.value_of("REPEATS") {
+                options.repeats = repeats.parse()?;
+            }
+            if let Some(seed) = matches.value_of("SEED") {
+                options.seed = seed.parse()?;
+            }
+            let runs = bench::run(&storage, &options)?;
+            bench::print_report(&options, &runs);
+        }
+        Some(("bonuses", _matches)) => {
+            bonus::BonusGraph::build(&storage)?.print_report();
+        }
+        Some(("score", matches)) => {
+            let best = match matches.value_of("BEST") {
+                Some(path) => score::load_best_dislikes(std::path::Path::new(path))?,
+                None => Default::default(),
+            };
+            score::print_report(&score::estimate(&storage, &best)?);
+        }
+        Some(("stats", matches)) => {
+            let format = matches.value_of("FORMAT").unwrap().parse()?;
+            stats::print(&stats::collect(&storage)?, format)?;
+        }
         _ => (),
     }
     Ok(())
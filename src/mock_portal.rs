@@ -0,0 +1,228 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde_json::json;
+
+use crate::common::*;
+use crate::portal::Session;
+use crate::problem::{Pose, ProblemId};
+use crate::storage::Storage;
+
+// Makes the solutions folders of the tests unique.
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// Local stand-in for the contest portal in the tests: serves the problems from the storage and
+// scores the submitted poses with our own validation. The next requests can be made to fail and
+// the verdicts to stay pending for a few polls.
+pub struct MockPortal {
+    pub url: String,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    // Verdicts by "problem/pose id", with the polls left to answer PENDING to.
+    submissions: HashMap<String, (usize, serde_json::Value)>,
+    // Statuses to answer the next requests of a method with, instead of handling them.
+    failures: HashMap<String, VecDeque<u16>>,
+    pending_polls: usize,
+    requests: usize,
+}
+
+impl MockPortal {
+    // Serves on a free local port from a background thread, until the tests exit.
+    pub fn start(storage: Storage) -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let state = Arc::new(Mutex::new(State::default()));
+        let server_state = state.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let result = match stream {
+                    Ok(stream) => handle(&storage, &server_state, stream),
+                    Err(e) => Err(e.into()),
+                };
+                if let Err(e) = result {
+                    warn!("Failed to handle a request: {}", e);
+                }
+            }
+        });
+        Ok(MockPortal { url, state })
+    }
+
+    // Session with short delays talking to this portal.
+    pub fn session(&self) -> Session {
+        Session::local(&self.url)
+    }
+
+    // Answers the next requests with the method with these statuses.
+    pub fn fail_next(&self, method: &str, statuses: &[u16]) {
+        let mut state = self.state.lock().unwrap();
+        let failures = state.failures.entry(method.to_owned()).or_default();
+        failures.extend(statuses);
+    }
+
+    // Makes the verdicts of the next submissions pending for that many polls.
+    pub fn set_pending_polls(&self, polls: usize) {
+        self.state.lock().unwrap().pending_polls = polls;
+    }
+
+    // Requests handled so far, the failed ones included.
+    pub fn requests(&self) -> usize {
+        self.state.lock().unwrap().requests
+    }
+}
+
+// Solutions folder removed when dropped.
+pub struct TempDir(PathBuf);
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+// Storage with the bundled problems and an empty solutions folder of its own.
+pub fn storage() -> (Storage, TempDir) {
+    let solutions = std::env::temp_dir().join(format!(
+        "icfpc2021-test-{}-{}",
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::create_dir_all(&solutions).unwrap();
+    let problems = Path::new(env!("CARGO_MANIFEST_DIR")).join("problems");
+    let storage = Storage::new(&problems, &solutions).unwrap();
+    (storage, TempDir(solutions))
+}
+
+fn handle(storage: &Storage, state: &Mutex<State>, mut stream: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_owned();
+    let path = parts.next().unwrap_or_default().to_owned();
+
+    let mut content_length = 0;
+    let mut authorized = false;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            match &name.trim().to_ascii_lowercase()[..] {
+                "content-length" => content_length = value.trim().parse()?,
+                "authorization" => authorized = value.trim().starts_with("Bearer "),
+                _ => {}
+            }
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    let (status, response) = {
+        let mut state = state.lock().unwrap();
+        state.requests += 1;
+        let failure = state
+            .failures
+            .get_mut(&method)
+            .and_then(|failures| failures.pop_front());
+        match failure {
+            Some(status) => (status, json!({"error": "Injected failure"}).to_string()),
+            None if !authorized => (403, json!({"error": "Missing API token"}).to_string()),
+            None => route(storage, &mut state, &method, &path, &body),
+        }
+    };
+    info!("{} {} -> {}", method, path, status);
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        match status {
+            200 => "OK",
+            400 => "Bad Request",
+            403 => "Forbidden",
+            404 => "Not Found",
+            429 => "Too Many Requests",
+            _ => "Error",
+        },
+        response.len(),
+        response
+    )?;
+    Ok(())
+}
+
+fn route(
+    storage: &Storage,
+    state: &mut State,
+    method: &str,
+    path: &str,
+    body: &[u8],
+) -> (u16, String) {
+    let segments = path.trim_matches('/').split('/').collect::<Vec<_>>();
+    let response = match (method, &segments[..]) {
+        ("GET", ["api", "hello"]) => Ok(json!({"hello": "mock"}).to_string()),
+        ("GET", ["api", "problems", id]) => problem(storage, id),
+        ("POST", ["api", "problems", id, "solutions"]) => submit(storage, state, id, body),
+        ("GET", ["api", "problems", id, "solutions", pose_id]) => {
+            match state.submissions.get_mut(&format!("{}/{}", id, pose_id)) {
+                Some((polls, _)) if *polls > 0 => {
+                    *polls -= 1;
+                    Ok(json!({"state": "PENDING"}).to_string())
+                }
+                Some((_, verdict)) => Ok(verdict.to_string()),
+                None => Err((404, format!("No solution {} for problem {}", pose_id, id))),
+            }
+        }
+        _ => Err((404, format!("No route for {} {}", method, path))),
+    };
+    match response {
+        Ok(response) => (200, response),
+        Err((status, error)) => (status, json!({ "error": error }).to_string()),
+    }
+}
+
+fn problem(storage: &Storage, id: &str) -> std::result::Result<String, (u16, String)> {
+    let id: ProblemId = id
+        .parse()
+        .map_err(|_| (404, format!("No problem {}", id)))?;
+    storage
+        .problem_json(&id)
+        .map_err(|_| (404, format!("No problem {}", id)))
+}
+
+fn submit(
+    storage: &Storage,
+    state: &mut State,
+    id: &str,
+    body: &[u8],
+) -> std::result::Result<String, (u16, String)> {
+    let problem_id: ProblemId = id
+        .parse()
+        .map_err(|_| (404, format!("No problem {}", id)))?;
+    let problem = storage
+        .load_problem(&problem_id)
+        .map_err(|_| (404, format!("No problem {}", id)))?;
+    let pose =
+        Pose::from_json(body).map_err(|e| (400, format!("Failed to parse the pose: {}", e)))?;
+
+    let verdict = if pose.vertices.len() != problem.figure_for(&pose).vertices.len() {
+        json!({"state": "INVALID", "error": "Wrong number of vertices"})
+    } else if !problem.validate(&pose) {
+        json!({"state": "INVALID", "error": "Pose does not fit into the hole"})
+    } else {
+        json!({"state": "VALID", "dislikes": problem.dislikes(&pose)})
+    };
+    let pose_id = format!("mock-{}", state.submissions.len() + 1);
+    info!("Problem {} solution {}: {}", id, pose_id, verdict);
+    state.submissions.insert(
+        format!("{}/{}", id, pose_id),
+        (state.pending_polls, verdict),
+    );
+    Ok(json!({ "id": pose_id }).to_string())
+}
@@ -1,56 +1,285 @@
 use lazy_static;
-use reqwest::{self, blocking::Client};
+use reqwest::{
+    self,
+    blocking::{Client, RequestBuilder, Response},
+    StatusCode,
+};
+use serde_derive::Deserialize;
+use std::time::Duration;
 
-use crate::{common::*, problem::Pose};
+use crate::{
+    common::*,
+    problem::{Pose, ProblemId, Verdict},
+};
+
+pub const DEFAULT_API_URL: &str = "https://poses.live";
+// How often to ask for the verdict on a submission and how long to wait for it.
+const STATUS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const STATUS_TIMEOUT: Duration = Duration::from_secs(30);
+// Attempts of the idempotent requests on the transient errors, the delay doubles every time.
+const MAX_ATTEMPTS: u32 = 5;
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
 
 pub struct Session {
+    base_url: String,
     token: String,
     client: Client,
+    retry_backoff: Duration,
+    poll_interval: Duration,
 }
 
 lazy_static! {
     pub static ref SESSION: Session = {
         let token = std::env::var("API_TOKEN").expect("Set the API_TOKEN environment variable");
-        Session::new(token).expect("Failed to create a session")
+        // Points to another portal, e.g. a local copy.
+        let base_url = std::env::var("API_URL").unwrap_or_else(|_| DEFAULT_API_URL.to_owned());
+        Session::new(base_url, token)
     };
 }
 
 impl Session {
-    pub fn new(token: String) -> Result<Self> {
-        let client = Client::new();
-        client
-            .get("https://poses.live/api/hello")
-            .bearer_auth(&token)
-            .send()?
-            .error_for_status()?;
-        Ok(Session { token, client })
+    // Doesn't talk to the portal, so that the session can be created offline.
+    pub fn new(base_url: String, token: String) -> Self {
+        Session {
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            token,
+            client: Client::new(),
+            retry_backoff: RETRY_BACKOFF,
+            poll_interval: STATUS_POLL_INTERVAL,
+        }
     }
 
-    pub fn download_problem(&self, id: u64, path: &str) -> Result<()> {
-        let resp = self
-            .client
-            .get(format!("https://poses.live/api/problems/{}", id))
+    // Session with short delays for a local mock portal.
+    #[cfg(test)]
+    pub fn local(base_url: &str) -> Self {
+        Session {
+            client: Client::builder().no_proxy().build().unwrap(),
+            retry_backoff: Duration::from_millis(1),
+            poll_interval: Duration::from_millis(1),
+            ..Session::new(base_url.to_owned(), "test".to_owned())
+        }
+    }
+
+    // Checks that the portal is up and accepts the token.
+    pub fn hello(&self) -> Result<()> {
+        self.client
+            .get(self.url("/api/hello"))
             .bearer_auth(&self.token)
             .send()?
             .error_for_status()?;
-        std::fs::write(path, resp.text()?)?;
         Ok(())
     }
 
-    pub fn upload_solution(&self, id: u64, pose: &Pose) -> Result<()> {
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    pub fn download_problem(&self, id: u64, path: &str) -> Result<()> {
+        let data = self
+            .fetch_problem(id)?
+            .ok_or_else(|| anyhow::anyhow!("Problem {} does not exist", id))?;
+        std::fs::write(path, data)?;
+        Ok(())
+    }
+
+    // Problem JSON, None if there's no such problem.
+    pub fn fetch_problem(&self, id: u64) -> Result<Option<String>> {
+        let url = self.url(&format!("/api/problems/{}", id));
+        match self.send_with_retry(|| self.client.get(&url).bearer_auth(&self.token)) {
+            Ok(resp) => Ok(Some(resp.text()?)),
+            Err(e) if e.status() == Some(StatusCode::NOT_FOUND) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn send_with_retry(&self, request: impl Fn() -> RequestBuilder) -> reqwest::Result<Response> {
+        let mut backoff = self.retry_backoff;
+        let mut attempt = 1;
+        loop {
+            match request().send().and_then(|r| r.error_for_status()) {
+                Err(e) if attempt < MAX_ATTEMPTS && is_transient(&e) => {
+                    warn!("{}, retrying in {}s", e, backoff.as_secs_f64());
+                    std::thread::sleep(backoff);
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Returns the pose id assigned by the portal.
+    pub fn upload_solution(&self, id: &ProblemId, pose: &Pose) -> Result<String> {
         let data = pose
             .to_json()?
             .as_bytes()
             .iter()
             .cloned()
             .collect::<Vec<_>>();
-        let _ = self
+        let resp = self
             .client
-            .post(format!("https://poses.live/api/problems/{}/solutions", id))
+            .post(self.url(&format!("/api/problems/{}/solutions", id)))
             .bearer_auth(&self.token)
             .body(data)
             .send()?
             .error_for_status()?;
-        Ok(())
+        let submission: RawSubmission = serde_json::from_str(&resp.text()?)?;
+        Ok(submission.id)
+    }
+
+    pub fn solution_status(&self, id: &ProblemId, pose_id: &str) -> Result<SolutionStatus> {
+        let url = self.url(&format!("/api/problems/{}/solutions/{}", id, pose_id));
+        let resp = self.send_with_retry(|| self.client.get(&url).bearer_auth(&self.token))?;
+        Ok(serde_json::from_str(&resp.text()?)?)
+    }
+
+    // Polls until the portal decides on the submission, the result may still be pending after
+    // the timeout.
+    pub fn wait_for_status(&self, id: &ProblemId, pose_id: &str) -> Result<SolutionStatus> {
+        let deadline = std::time::Instant::now() + STATUS_TIMEOUT;
+        loop {
+            let status = self.solution_status(id, pose_id)?;
+            if status.state != Verdict::Pending || std::time::Instant::now() > deadline {
+                return Ok(status);
+            }
+            std::thread::sleep(self.poll_interval);
+        }
+    }
+}
+
+fn is_transient(e: &reqwest::Error) -> bool {
+    e.is_timeout()
+        || e.is_connect()
+        || e.status()
+            .map(|s| s.is_server_error() || s == StatusCode::TOO_MANY_REQUESTS)
+            .unwrap_or(false)
+}
+
+// Whether the portal refused the request for good, e.g. a malformed pose. The token problems
+// are not about the request, they don't count.
+pub fn is_refused(e: &anyhow::Error) -> bool {
+    let e = match e.downcast_ref::<reqwest::Error>() {
+        Some(e) => e,
+        None => return false,
+    };
+    match e.status() {
+        Some(StatusCode::UNAUTHORIZED) | Some(StatusCode::FORBIDDEN) | None => false,
+        Some(status) => status.is_client_error() && !is_transient(e),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SolutionStatus {
+    pub state: Verdict,
+    pub dislikes: Option<u64>,
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawSubmission {
+    id: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::mock_portal::{self, MockPortal};
+
+    // A pose with no dislikes for problem 13, and one outside of its hole.
+    const VALID_POSE: &str = r#"{"vertices": [[0, 20], [20, 0], [20, 40], [40, 20]]}"#;
+    const INVALID_POSE: &str = r#"{"vertices": [[15, 21], [34, 0], [0, 45], [19, 24]]}"#;
+
+    #[test]
+    fn retries_transient_errors() {
+        let (storage, _dir) = mock_portal::storage();
+        let portal = MockPortal::start(storage).unwrap();
+        let session = portal.session();
+        portal.fail_next("GET", &[503, 429]);
+        assert!(session.fetch_problem(13).unwrap().is_some());
+        assert_eq!(portal.requests(), 3);
+    }
+
+    #[test]
+    fn stops_on_permanent_errors_and_after_max_attempts() {
+        let (storage, _dir) = mock_portal::storage();
+        let portal = MockPortal::start(storage).unwrap();
+        let session = portal.session();
+        portal.fail_next("GET", &[400]);
+        assert!(session.fetch_problem(13).is_err());
+        assert_eq!(portal.requests(), 1);
+        portal.fail_next("GET", &[500; MAX_ATTEMPTS as usize]);
+        assert!(session.fetch_problem(13).is_err());
+        assert_eq!(portal.requests(), 1 + MAX_ATTEMPTS as usize);
+        // A missing problem is not an error.
+        assert!(session.fetch_problem(1_000_000).unwrap().is_none());
+    }
+
+    #[test]
+    fn transient_errors() {
+        let (storage, _dir) = mock_portal::storage();
+        let portal = MockPortal::start(storage).unwrap();
+        let session = portal.session();
+        let error = |status| {
+            portal.fail_next("GET", &[status]);
+            session
+                .client
+                .get(session.url("/api/hello"))
+                .send()
+                .and_then(|r| r.error_for_status())
+                .unwrap_err()
+        };
+        assert!(is_transient(&error(500)));
+        assert!(is_transient(&error(503)));
+        assert!(is_transient(&error(429)));
+        assert!(!is_transient(&error(400)));
+        assert!(!is_transient(&error(404)));
+        // Nothing listens on a port just freed.
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let e = session
+            .client
+            .get(format!("http://{}/api/hello", addr))
+            .send()
+            .unwrap_err();
+        assert!(is_transient(&e));
+    }
+
+    #[test]
+    fn uploads_and_waits_for_the_verdict() {
+        let (storage, _dir) = mock_portal::storage();
+        let portal = MockPortal::start(storage).unwrap();
+        let session = portal.session();
+        let id = ProblemId::from(13);
+
+        portal.set_pending_polls(2);
+        let pose = Pose::from_json(VALID_POSE.as_bytes()).unwrap();
+        let pose_id = session.upload_solution(&id, &pose).unwrap();
+        let status = session.wait_for_status(&id, &pose_id).unwrap();
+        assert_eq!(status.state, Verdict::Valid);
+        assert_eq!(status.dislikes, Some(0));
+        // The upload and three polls.
+        assert_eq!(portal.requests(), 4);
+
+        portal.set_pending_polls(0);
+        let pose = Pose::from_json(INVALID_POSE.as_bytes()).unwrap();
+        let pose_id = session.upload_solution(&id, &pose).unwrap();
+        let status = session.wait_for_status(&id, &pose_id).unwrap();
+        assert_eq!(status.state, Verdict::Invalid);
+        assert!(status.error.is_some());
+    }
+
+    #[test]
+    fn uploads_are_not_retried() {
+        let (storage, _dir) = mock_portal::storage();
+        let portal = MockPortal::start(storage).unwrap();
+        let session = portal.session();
+        portal.fail_next("POST", &[503]);
+        let pose = Pose::from_json(VALID_POSE.as_bytes()).unwrap();
+        assert!(session.upload_solution(&13.into(), &pose).is_err());
+        assert_eq!(portal.requests(), 1);
     }
 }
@@ -4,12 +4,87 @@ use geo::algorithm::line_intersection::{line_intersection, LineIntersection};
 use geo::relate::Relate;
 use ordered_float::NotNan;
 use serde_derive::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::collections::HashSet;
 
 use crate::common::*;
 
 pub type Point = geo::Coordinate<i64>;
 
+// Problem id, the name of its file without the extension. The portal numbers the problems, so
+// the numeric ids sort as numbers and go to the JSON files as numbers.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ProblemId(String);
+
+impl ProblemId {
+    pub fn number(&self) -> Option<u32> {
+        self.0.parse().ok()
+    }
+
+    // Numbers first, then the other ids by name.
+    fn sort_key(&self) -> (bool, Option<u32>, &str) {
+        let number = self.number();
+        (number.is_none(), number, &self.0)
+    }
+}
+
+impl From<u32> for ProblemId {
+    fn from(id: u32) -> Self {
+        ProblemId(id.to_string())
+    }
+}
+
+impl std::str::FromStr for ProblemId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.is_empty() || s.starts_with('.') || s.contains(&['/', '\\'][..]) {
+            return Err(anyhow::anyhow!("Invalid problem id '{}'", s));
+        }
+        // "007" and "7" are the same problem.
+        Ok(match s.parse::<u32>() {
+            Ok(id) => id.into(),
+            Err(_) => ProblemId(s.to_owned()),
+        })
+    }
+}
+
+impl std::fmt::Display for ProblemId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.pad(&self.0)
+    }
+}
+
+impl Ord for ProblemId {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.sort_key().cmp(&other.sort_key())
+    }
+}
+
+impl PartialOrd for ProblemId {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl serde::Serialize for ProblemId {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> std::result::Result<S::Ok, S::Error> {
+        match self.number() {
+            Some(id) => s.serialize_u32(id),
+            None => s.serialize_str(&self.0),
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ProblemId {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> std::result::Result<Self, D::Error> {
+        match <RawProblemId as serde::Deserialize>::deserialize(d)? {
+            RawProblemId::Number(id) => Ok(id.into()),
+            RawProblemId::Name(name) => name.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Edge {
     pub v0: usize,
@@ -98,6 +173,17 @@ impl Figure {
         (p.x - q.x).pow(2) + (p.y - q.y).pow(2)
     }
 
+    // Largest distance between two vertices of the figure.
+    pub fn diameter(&self) -> f64 {
+        let mut max_d2 = 0;
+        for (i, &p) in self.vertices.iter().enumerate() {
+            for &q in &self.vertices[i + 1..] {
+                max_d2 = max_d2.max(Figure::distance_squared_int(p, q));
+            }
+        }
+        (max_d2 as f64).sqrt()
+    }
+
     pub fn edge_len2(&self, idx: usize, pose: &Pose) -> f64 {
         let e = &self.edges[idx];
         let p = pose.vertices[e.v0];
@@ -125,6 +211,11 @@ impl Figure {
         self.edge_len2(idx, pose) - self.edges[idx].len2
     }
 
+    // Relative deformation of the edge, GLOBALIST limits the sum of them.
+    pub fn edge_deformation(&self, idx: usize, pose: &Pose) -> f64 {
+        (self.edge_len2(idx, pose) / self.edges[idx].len2 - 1.0).abs()
+    }
+
     pub fn test_edge_len2(&self, idx: usize, pose: &Pose) -> EdgeTestResult {
         let diff = self.edge_len2_diff(idx, pose);
         let allowed = self.epsilon * self.edges[idx].len2;
@@ -137,6 +228,38 @@ impl Figure {
         }
     }
 
+    pub fn find_edge(&self, a: usize, b: usize) -> Option<usize> {
+        self.edges
+            .iter()
+            .position(|e| (e.v0, e.v1) == (a, b) || (e.v0, e.v1) == (b, a))
+    }
+
+    // Figure with the edge split by a new last vertex in its middle, as BREAK_A_LEG does.
+    pub fn break_a_leg(&self, idx: usize) -> Figure {
+        let e = &self.edges[idx];
+        let (p, q) = (self.vertices[e.v0], self.vertices[e.v1]);
+        let mut vertices = self.vertices.clone();
+        vertices.push(Point {
+            x: (p.x + q.x) / 2,
+            y: (p.y + q.y) / 2,
+        });
+        let middle = vertices.len() - 1;
+        // Both halves are checked against half of the original length.
+        let len2 = e.len2 / 4.0;
+        let mut edges = self.edges.clone();
+        edges[idx] = Edge {
+            v0: e.v0,
+            v1: middle,
+            len2,
+        };
+        edges.push(Edge {
+            v0: middle,
+            v1: e.v1,
+            len2,
+        });
+        Figure::new(vertices, edges, self.epsilon)
+    }
+
     pub fn to_float_point(p: Point) -> geo::Point<f64> {
         geo::Point::new(p.x as f64, p.y as f64)
     }
@@ -194,7 +317,7 @@ impl Figure {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum BonusType {
     Globalist, // Shared epsilon
     BreakALeg, // Divide an edge into two
@@ -229,12 +352,12 @@ impl From<BonusType> for String {
 pub struct BonusUnlock {
     pub position: Point,
     pub bonus: BonusType,
-    pub problem: u32,
+    pub problem: ProblemId,
 }
 
 #[derive(Clone, Debug)]
 pub struct Problem {
-    pub id: u32,
+    pub id: ProblemId,
     pub hole: Vec<Point>,
     pub poly: geo::Polygon<f64>,
     inside_points: Vec<Vec<bool>>,
@@ -244,10 +367,18 @@ pub struct Problem {
     precalced: bool,
     pub figure: Figure,
     pub bonuses: Vec<BonusUnlock>,
+    // Position of the bonus the solvers should collect, if any.
+    pub target_bonus: Option<Point>,
+    // Vertex the solvers can put outside of the hole with WALLHACK.
+    pub wallhack: Option<usize>,
+    // Edge the solvers can stretch in any way with SUPERFLEX.
+    pub superflex: Option<usize>,
+    // Whether the solvers share the epsilon between all edges with GLOBALIST.
+    pub globalist: bool,
 }
 
 impl Problem {
-    pub fn new(id: u32, hole: Vec<Point>, figure: Figure, bonuses: Vec<BonusUnlock>) -> Self {
+    pub fn new(id: ProblemId, hole: Vec<Point>, figure: Figure, bonuses: Vec<BonusUnlock>) -> Self {
         let mut border: Vec<geo::Coordinate<f64>> = hole
             .clone()
             .into_iter()
@@ -271,6 +402,10 @@ impl Problem {
             precalced: false,
             figure,
             bonuses,
+            target_bonus: None,
+            wallhack: None,
+            superflex: None,
+            globalist: false,
         }
     }
 
@@ -299,7 +434,7 @@ impl Problem {
         self.precalced = true;
     }
 
-    pub fn from_json(id: u32, data: &[u8]) -> Result<Self> {
+    pub fn from_json(id: ProblemId, data: &[u8]) -> Result<Self> {
         let RawProblem {
             hole,
             figure: RawFigure { vertices, edges },
@@ -341,6 +476,18 @@ impl Problem {
         ))
     }
 
+    // Contest score of a valid pose with zero dislikes as the best result.
+    pub fn max_score(&self) -> f64 {
+        let size = self.figure.vertices.len() * self.figure.edges.len() * self.hole.len();
+        1000.0 * (size as f64 / 6.0).log2()
+    }
+
+    // Contest score of a valid pose, given the best dislikes among all the teams.
+    pub fn score(&self, dislikes: u64, min_dislikes: u64) -> u64 {
+        let ratio = (min_dislikes as f64 + 1.0) / (dislikes as f64 + 1.0);
+        (self.max_score() * ratio.sqrt()).ceil() as u64
+    }
+
     pub fn dislikes(&self, pose: &Pose) -> u64 {
         let sum: f64 = self
             .hole
@@ -357,15 +504,41 @@ impl Problem {
         sum.trunc() as u64
     }
 
+    // Figure of the pose, with an edge split if the pose uses BREAK_A_LEG.
+    pub fn figure_for(&self, pose: &Pose) -> Cow<Figure> {
+        if pose.vertices.len() == self.figure.vertices.len() + 1 {
+            let split = pose
+                .bonuses
+                .iter()
+                .filter(|b| b.bonus == BonusType::BreakALeg)
+                .find_map(|b| b.edge)
+                .and_then(|(a, b)| self.figure.find_edge(a, b));
+            if let Some(idx) = split {
+                return Cow::Owned(self.figure.break_a_leg(idx));
+            }
+        }
+        Cow::Borrowed(&self.figure)
+    }
+
     pub fn contains(&self, pose: &Pose) -> bool {
+        // With WALLHACK one vertex and its edges can be outside.
+        let wallhack = pose.uses(BonusType::WallHack);
+        let mut outside = None;
         // 1 - vertices are inside
-        for &p in &pose.vertices {
+        for (idx, &p) in pose.vertices.iter().enumerate() {
             if !is_point_belongs_to_poly(&self.poly, p) {
+                if wallhack && outside.is_none() {
+                    outside = Some(idx);
+                    continue;
+                }
                 return false;
             }
         }
         // 2 - edges are inside
-        for e in &self.figure.edges {
+        for e in &self.figure_for(pose).edges {
+            if outside == Some(e.v0) || outside == Some(e.v1) {
+                continue;
+            }
             if !is_segment_belongs_to_poly(&self.poly, (pose.vertices[e.v0], pose.vertices[e.v1])) {
                 return false;
             }
@@ -373,6 +546,15 @@ impl Problem {
         true
     }
 
+    // Number of the lattice points inside the hole, including its border.
+    pub fn inside_points_count(&self) -> usize {
+        assert!(self.precalced);
+        self.inside_points
+            .iter()
+            .map(|column| column.iter().filter(|&&inside| inside).count())
+            .sum()
+    }
+
     pub fn point_on_hole(&self, p: &Point) -> bool {
         return self.hole.contains(p);
     }
@@ -399,9 +581,28 @@ impl Problem {
         self.inside_segments.contains(&(a, b)) || self.inside_segments.contains(&(b, a))
     }
 
+    // Same as `contains_segment` but checks the polygon directly instead of the precalc.
+    pub fn contains_segment_exact(&self, segment: (Point, Point)) -> bool {
+        is_segment_belongs_to_poly(&self.poly, segment)
+    }
+
     pub fn correct_length(&self, pose: &Pose) -> bool {
-        for idx in 0..self.figure.edges.len() {
-            if self.figure.test_edge_len2(idx, pose) != EdgeTestResult::Ok {
+        let figure = self.figure_for(pose);
+        // With GLOBALIST only the total deformation is limited.
+        if pose.uses(BonusType::Globalist) {
+            let total: f64 = (0..figure.edges.len())
+                .map(|idx| figure.edge_deformation(idx, pose))
+                .sum();
+            return total <= figure.edges.len() as f64 * figure.epsilon;
+        }
+        // With SUPERFLEX one edge can have any length.
+        let mut flex = pose.uses(BonusType::SuperFlex);
+        for idx in 0..figure.edges.len() {
+            if figure.test_edge_len2(idx, pose) != EdgeTestResult::Ok {
+                if flex {
+                    flex = false;
+                    continue;
+                }
                 return false;
             }
         }
@@ -420,6 +621,24 @@ impl Problem {
         return self.poly.euclidean_distance(&p);
     }
 
+    // Distance from the target bonus to the closest vertex of the pose, 0 without a target.
+    pub fn target_bonus_distance(&self, pose: &Pose) -> f64 {
+        match self.target_bonus {
+            Some(target) => pose
+                .vertices
+                .iter()
+                .map(|&p| Figure::distance_squared(p, target).sqrt())
+                .fold(f64::INFINITY, f64::min),
+            None => 0.0,
+        }
+    }
+
+    // Whether the pose collects the target bonus, None without a target.
+    pub fn target_bonus_unlocked(&self, pose: &Pose) -> Option<bool> {
+        self.target_bonus
+            .map(|target| pose.vertices.contains(&target))
+    }
+
     pub fn edge_intersections(&self, src_pos: Point, dst_pos: Point) -> f64 {
         let edge = geo::Line::new(src_pos.convert(), dst_pos.convert());
         if self.poly.contains(&edge) {
@@ -470,10 +689,12 @@ impl Problem {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub struct BonusUse {
     pub bonus: BonusType,
-    pub problem: u32,
+    pub problem: ProblemId,
+    // Vertices of the edge split by BREAK_A_LEG.
+    pub edge: Option<(usize, usize)>,
 }
 
 #[derive(Clone, Debug, Default)]
@@ -485,8 +706,38 @@ pub struct Pose {
 
 impl Pose {
     pub fn from_json(data: &[u8]) -> Result<Self> {
-        let RawPose { vertices, bonuses } = serde_json::from_slice(data)?;
-        Ok(Pose {
+        let pose: RawPose = serde_json::from_slice(data)?;
+        Ok(pose.into())
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(&RawPose::from(self))?)
+    }
+
+    pub fn uses(&self, bonus: BonusType) -> bool {
+        self.bonuses.iter().any(|b| b.bonus == bonus)
+    }
+
+    // Splits the edge of the figure with the BREAK_A_LEG bonus from `source`, the new vertex is
+    // placed in the middle like in `Figure::break_a_leg`.
+    pub fn break_a_leg(&mut self, figure: &Figure, idx: usize, source: ProblemId) {
+        let e = &figure.edges[idx];
+        let (p, q) = (self.vertices[e.v0], self.vertices[e.v1]);
+        self.vertices.push(Point {
+            x: (p.x + q.x) / 2,
+            y: (p.y + q.y) / 2,
+        });
+        self.bonuses = vec![BonusUse {
+            bonus: BonusType::BreakALeg,
+            problem: source,
+            edge: Some((e.v0, e.v1)),
+        }];
+    }
+}
+
+impl From<RawPose> for Pose {
+    fn from(RawPose { vertices, bonuses }: RawPose) -> Self {
+        Pose {
             vertices: vertices
                 .into_iter()
                 .map(|p| Point { x: p[0], y: p[1] })
@@ -496,36 +747,61 @@ impl Pose {
                 .map(|b| BonusUse {
                     bonus: b.bonus[..].into(),
                     problem: b.problem,
+                    edge: b.edge.map(|e| (e[0], e[1])),
                 })
                 .collect(),
             optimal: None,
-        })
+        }
     }
+}
 
-    pub fn to_json(&self) -> Result<String> {
-        let pose = RawPose {
-            vertices: self.vertices.iter().map(|p| vec![p.x, p.y]).collect(),
-            bonuses: self
+impl From<&Pose> for RawPose {
+    fn from(pose: &Pose) -> Self {
+        RawPose {
+            vertices: pose.vertices.iter().map(|p| vec![p.x, p.y]).collect(),
+            bonuses: pose
                 .bonuses
                 .iter()
                 .map(|b| RawBonusUse {
                     bonus: b.bonus.into(),
-                    problem: b.problem,
+                    problem: b.problem.clone(),
+                    edge: b.edge.map(|(a, b)| vec![a, b]),
                 })
                 .collect(),
-        };
-        Ok(serde_json::to_string(&pose)?)
+        }
     }
 }
 
+// Portal verdict on a submitted pose.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Verdict {
+    Pending,
+    Valid,
+    Invalid,
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct ServerState {
+    // Dislikes of the best pose accepted by the portal.
     pub dislikes: u64,
+    // The last submission: portal pose id, seconds since the Unix epoch, the dislikes we
+    // computed for it and the portal verdict.
+    pub submission_id: Option<String>,
+    pub submitted_at: Option<u64>,
+    pub submitted_dislikes: Option<u64>,
+    pub verdict: Option<Verdict>,
 }
 
 impl ServerState {
     pub fn new() -> Self {
-        ServerState { dislikes: u64::MAX }
+        ServerState {
+            dislikes: u64::MAX,
+            submission_id: None,
+            submitted_at: None,
+            submitted_dislikes: None,
+            verdict: None,
+        }
     }
 
     pub fn from_json(data: &[u8]) -> Result<Self> {
@@ -537,32 +813,137 @@ impl ServerState {
     }
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct SolutionState {
     pub dislikes: u64,
     pub valid: bool,
     pub optimal: bool,
 }
 
-impl SolutionState {
+pub struct Solution {
+    pub id: ProblemId,
+    pub pose: Pose,
+    pub state: SolutionState,
+    pub server_state: ServerState,
+}
+
+// An entry of the append-only solutions history of a problem, with where the pose came from.
+pub struct HistoryEntry {
+    // Seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub solver: String,
+    pub seed: Option<u64>,
+    pub runtime_ms: u64,
+    pub state: SolutionState,
+    pub pose: Pose,
+}
+
+impl HistoryEntry {
+    pub fn new(
+        solver: &str,
+        seed: Option<u64>,
+        runtime: std::time::Duration,
+        solution: &Solution,
+    ) -> Self {
+        HistoryEntry {
+            timestamp: unix_time(),
+            solver: solver.to_owned(),
+            seed,
+            runtime_ms: runtime.as_millis() as u64,
+            state: solution.state.clone(),
+            pose: solution.pose.clone(),
+        }
+    }
+
     pub fn from_json(data: &[u8]) -> Result<Self> {
-        Ok(serde_json::from_slice(data)?)
+        let entry: RawHistoryEntry = serde_json::from_slice(data)?;
+        Ok(HistoryEntry {
+            timestamp: entry.timestamp,
+            solver: entry.solver,
+            seed: entry.seed,
+            runtime_ms: entry.runtime_ms,
+            state: entry.state,
+            pose: entry.pose.into(),
+        })
     }
 
     pub fn to_json(&self) -> Result<String> {
-        Ok(serde_json::to_string(&self)?)
+        Ok(serde_json::to_string(&RawHistoryEntry {
+            timestamp: self.timestamp,
+            solver: self.solver.clone(),
+            seed: self.seed,
+            runtime_ms: self.runtime_ms,
+            state: self.state.clone(),
+            pose: RawPose::from(&self.pose),
+        })?)
     }
 }
 
-pub struct Solution {
-    pub id: u32,
+// A pose waiting in the outbox to be submitted to the portal, or the record of a sent one.
+pub struct OutboxEntry {
+    pub id: ProblemId,
+    pub dislikes: u64,
+    // Seconds since the Unix epoch.
+    pub queued_at: u64,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    // Portal pose id and the time of the submission, once it's sent.
+    pub pose_id: Option<String>,
+    pub sent_at: Option<u64>,
     pub pose: Pose,
-    pub state: SolutionState,
-    pub server_state: ServerState,
+}
+
+impl OutboxEntry {
+    pub fn new(solution: &Solution) -> Self {
+        OutboxEntry {
+            id: solution.id.clone(),
+            dislikes: solution.state.dislikes,
+            queued_at: unix_time(),
+            attempts: 0,
+            last_error: None,
+            pose_id: None,
+            sent_at: None,
+            pose: solution.pose.clone(),
+        }
+    }
+
+    pub fn from_json(data: &[u8]) -> Result<Self> {
+        let entry: RawOutboxEntry = serde_json::from_slice(data)?;
+        Ok(OutboxEntry {
+            id: entry.id,
+            dislikes: entry.dislikes,
+            queued_at: entry.queued_at,
+            attempts: entry.attempts,
+            last_error: entry.last_error,
+            pose_id: entry.pose_id,
+            sent_at: entry.sent_at,
+            pose: entry.pose.into(),
+        })
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(&RawOutboxEntry {
+            id: self.id.clone(),
+            dislikes: self.dislikes,
+            queued_at: self.queued_at,
+            attempts: self.attempts,
+            last_error: self.last_error.clone(),
+            pose_id: self.pose_id.clone(),
+            sent_at: self.sent_at,
+            pose: RawPose::from(&self.pose),
+        })?)
+    }
 }
 
 // Serialization helper types below
 
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawProblemId {
+    Number(u32),
+    Name(String),
+}
+
 #[derive(Deserialize)]
 struct RawFigure {
     pub vertices: Vec<Vec<i64>>,
@@ -584,15 +965,40 @@ struct RawPose {
     pub bonuses: Vec<RawBonusUse>,
 }
 
+#[derive(Deserialize, Serialize)]
+struct RawOutboxEntry {
+    pub id: ProblemId,
+    pub dislikes: u64,
+    pub queued_at: u64,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub pose_id: Option<String>,
+    pub sent_at: Option<u64>,
+    pub pose: RawPose,
+}
+
+#[derive(Deserialize, Serialize)]
+struct RawHistoryEntry {
+    pub timestamp: u64,
+    pub solver: String,
+    pub seed: Option<u64>,
+    pub runtime_ms: u64,
+    #[serde(flatten)]
+    pub state: SolutionState,
+    pub pose: RawPose,
+}
+
 #[derive(Deserialize)]
 struct RawBonusUnlock {
     pub position: Vec<i64>,
     pub bonus: String,
-    pub problem: u32,
+    pub problem: ProblemId,
 }
 
 #[derive(Deserialize, Serialize)]
 struct RawBonusUse {
     pub bonus: String,
-    pub problem: u32,
+    pub problem: ProblemId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edge: Option<Vec<usize>>,
 }
@@ -9,10 +9,12 @@ use geomath::prelude::coordinates::Polar;
 use ordered_float::NotNan;
 use raylib::prelude::*;
 
+use crate::bonus::bonus_source;
+use crate::common::*;
 use crate::problem::*;
 use crate::solver::Solver;
+use crate::storage::{self, Storage};
 use crate::transform::Transform;
-use crate::{common::*, storage};
 
 struct GuiState {
     // Coord translator
@@ -35,6 +37,7 @@ struct GuiState {
 
     // Problem browser
     pub problems: Vec<CString>,
+    pub problem_ids: Vec<ProblemId>,
     pub problems_focus_idx: i32,
     pub problems_scroll_idx: i32,
     pub problems_selected: i32,
@@ -44,14 +47,19 @@ struct GuiState {
 }
 
 impl GuiState {
-    pub fn new(problem: &Problem, solver: &'static Box<dyn Solver>, id: u32) -> Result<Self> {
+    pub fn new(
+        storage: &Storage,
+        problem: &Problem,
+        solver: &'static Box<dyn Solver>,
+        id: &ProblemId,
+    ) -> Result<Self> {
         let translator = Self::create_translator(problem);
 
-        let problems_count = storage::get_problems_count();
-        let problems = (1..=problems_count)
-            .into_iter()
-            .map(
-                |id| match storage::load_solution(id).expect("Failed to load a solution") {
+        let problem_ids = storage.list_problems()?;
+        let problems = problem_ids
+            .iter()
+            .map(|id| -> Result<CString> {
+                Ok(match storage.load_solution(id)? {
                     Some(s) => {
                         if s.server_state.dislikes == u64::MAX {
                             CString::new(format!("#152#{}", id)).unwrap()
@@ -64,9 +72,9 @@ impl GuiState {
                         }
                     }
                     None => CString::new(format!("#152#{}", id)).unwrap(),
-                },
-            )
-            .collect();
+                })
+            })
+            .collect::<Result<_>>()?;
 
         Ok(GuiState {
             translator,
@@ -78,16 +86,21 @@ impl GuiState {
             rotate_pivot: None,
             rotate_vertices_copy: vec![],
             paths: vec![],
+            problems_selected: problem_ids
+                .iter()
+                .position(|i| i == id)
+                .map(|i| i as i32)
+                .unwrap_or(-1),
             problems,
+            problem_ids,
             problems_focus_idx: 0,
             problems_scroll_idx: 0,
-            problems_selected: (id - 1) as i32,
             solver,
         })
     }
 
-    pub fn load_problem(&mut self) -> Result<Problem> {
-        let problem = storage::load_problem((self.problems_selected + 1) as u32)?;
+    pub fn load_problem(&mut self, storage: &Storage) -> Result<Problem> {
+        let problem = storage.load_problem(&self.problem_ids[self.problems_selected as usize])?;
         self.translator = Self::create_translator(&problem);
         self.dragged_point = None;
         self.viewport_drag_point = None;
@@ -208,7 +221,7 @@ fn render_gui(
     let mut text = b"\
 Tools: Q - Pull, Shift+Q - Push, E - Center Illegal, Shift+E - Center All, C - Flip Horz, V - Flip Vert, W - Fold (hold), R - Rotate (hold)\n\
 Selection/Navigation: Ctrl+A - Select All, Shift adds, Ctrl removes, Z - Select Adjacent, X - Invert Selection, RMB - Drag Viewport, Scrollwheel - Zoom
-Misc: S - Save, D - Step Solver, F - Run Solver, Shift+L - Reset Selected, Ctrl+L - Reset Solution\n\
+Misc: S - Save, D - Step Solver, F - Run Solver, Shift+L - Reset Selected, Ctrl+L - Reset Solution, B - Break a Leg between 2 selected\n\
 "
     .to_owned();
     d.gui_text_box_multi(
@@ -450,9 +463,10 @@ fn hit_test_rect(pose: &Pose, min: Point, max: Point) -> Vec<usize> {
 }
 
 pub fn interact<'a>(
+    storage: &Storage,
     solution_path: Option<&Path>,
     solver: &'static Box<dyn Solver>,
-    id: u32,
+    id: &ProblemId,
 ) -> Result<()> {
     use raylib::consts::*;
 
@@ -461,22 +475,23 @@ pub fn interact<'a>(
 
     let (mut rh, thread) = raylib::init().size(WINDOW_WIDTH, WINDOW_HEIGHT).build();
 
-    let mut problem = storage::load_problem(id)?;
-    let mut state = GuiState::new(&problem, solver, id)?;
+    let mut problem = storage.load_problem(id)?;
+    let mut state = GuiState::new(storage, &problem, solver, id)?;
 
     let pose = match solution_path {
         Some(p) => storage::load_custom_solution(p)?,
         None => {
-            let solution = storage::load_solution(id)?;
+            let solution = storage.load_solution(id)?;
             solution
                 .map(|s| s.pose)
                 .unwrap_or_else(|| problem.figure.get_default_pose())
         }
     };
+    problem.figure = problem.figure_for(&pose).into_owned();
 
     let mut gen = state
         .solver
-        .solve_gen(problem.clone(), Rc::new(RefCell::new(pose)));
+        .solve_gen(problem.clone(), Rc::new(RefCell::new(pose)), storage);
     let mut pose = gen.resume().unwrap();
 
     while !rh.window_should_close() {
@@ -489,14 +504,17 @@ pub fn interact<'a>(
 
             if selected_problem != -1 && state.problems_selected != selected_problem {
                 state.problems_selected = selected_problem;
-                problem = state.load_problem()?;
-                let solution = storage::load_solution(problem.id)?;
+                problem = state.load_problem(storage)?;
+                let solution = storage.load_solution(&problem.id)?;
                 let initial_pose = solution
                     .map(|s| s.pose)
                     .unwrap_or_else(|| problem.figure.get_default_pose());
-                gen = state
-                    .solver
-                    .solve_gen(problem.clone(), Rc::new(RefCell::new(initial_pose)));
+                problem.figure = problem.figure_for(&initial_pose).into_owned();
+                gen = state.solver.solve_gen(
+                    problem.clone(),
+                    Rc::new(RefCell::new(initial_pose)),
+                    storage,
+                );
                 pose = gen.resume().unwrap();
             }
         }
@@ -740,7 +758,7 @@ pub fn interact<'a>(
                     }
                 }
                 KeyboardKey::KEY_S => {
-                    let id = (state.problems_selected + 1) as u32;
+                    let id = problem.id.clone();
                     let dislikes = problem.dislikes(&pose.borrow());
                     let s = SolutionState {
                         dislikes,
@@ -748,14 +766,46 @@ pub fn interact<'a>(
                         optimal: dislikes == 0,
                     };
                     let solution = Solution {
-                        id,
+                        id: id.clone(),
                         pose: pose.borrow().clone(),
                         state: s,
-                        server_state: storage::load_server_state(id)?,
+                        server_state: storage.load_server_state(&id)?,
                     };
-                    storage::save_solution(&solution, None)?;
+                    storage.append_history(
+                        &id,
+                        &HistoryEntry::new("manual", None, time::Duration::default(), &solution),
+                    )?;
+                    storage.save_solution(&solution, None)?;
                     info!("Saved solution {} to the default solution folder", id);
                 }
+                KeyboardKey::KEY_B => {
+                    let selected = state.selected_points.iter().cloned().collect::<Vec<_>>();
+                    let edge = match selected[..] {
+                        [a, b] => problem.figure.find_edge(a, b),
+                        _ => None,
+                    };
+                    let split = pose.borrow().uses(BonusType::BreakALeg);
+                    let source = bonus_source(storage, &problem.id, BonusType::BreakALeg)?;
+                    match (edge, source) {
+                        _ if split => warn!("The figure already has a broken leg"),
+                        (None, _) => warn!("Select the two vertices of an edge to break it"),
+                        (_, None) => {
+                            warn!("No problem unlocks BREAK_A_LEG for problem {}", problem.id)
+                        }
+                        (Some(idx), Some(source)) => {
+                            let mut split_pose = pose.borrow().clone();
+                            split_pose.break_a_leg(&problem.figure, idx, source);
+                            problem.figure = problem.figure.break_a_leg(idx);
+                            // The solver has to work on the split figure too.
+                            gen = state.solver.solve_gen(
+                                problem.clone(),
+                                Rc::new(RefCell::new(split_pose)),
+                                storage,
+                            );
+                            pose = gen.resume().unwrap();
+                        }
+                    }
+                }
                 KeyboardKey::KEY_D => {
                     if let Some(temp) = gen.resume() {
                         pose = temp;
@@ -770,9 +820,12 @@ pub fn interact<'a>(
                             pose.borrow_mut().vertices[idx] = problem.figure.vertices[idx];
                         }
                     } else if rh.is_key_down(KeyboardKey::KEY_LEFT_CONTROL) {
+                        // Undo the edge split too.
+                        problem = storage.load_problem(&problem.id)?;
                         gen = state.solver.solve_gen(
                             problem.clone(),
                             Rc::new(RefCell::new(problem.figure.get_default_pose())),
+                            storage,
                         );
                         pose = gen.resume().unwrap();
                     }
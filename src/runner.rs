@@ -1,23 +1,135 @@
+use std::path::PathBuf;
+
 use rayon::prelude::*;
+use serde_derive::Serialize;
 
+use crate::common::*;
+use crate::problem::{HistoryEntry, Pose, Problem, ProblemId, Solution, SolutionState};
 use crate::solver::SOLVERS;
-use crate::{common::*, storage};
+use crate::storage::{self, Storage};
+
+// Where the solvers take their initial pose from
+#[derive(Clone, Debug)]
+pub enum StartPose {
+    // The original figure from the problem
+    Default,
+    // The current best solution
+    Best,
+    // The best solution found by the given solver (its subfolder)
+    Solver(String),
+    // A solution file, or a folder with N.solution files
+    Path(PathBuf),
+}
+
+impl From<&str> for StartPose {
+    fn from(s: &str) -> Self {
+        match s {
+            "default" => StartPose::Default,
+            "best" => StartPose::Best,
+            name if SOLVERS.contains_key(name) => StartPose::Solver(name.to_owned()),
+            path => StartPose::Path(PathBuf::from(path)),
+        }
+    }
+}
+
+impl StartPose {
+    // Also switches the problem to the figure of the pose, the split one for BREAK_A_LEG.
+    pub fn load(&self, storage: &Storage, problem: &mut Problem) -> Result<Pose> {
+        let pose = match self {
+            StartPose::Default => None,
+            StartPose::Best => storage.load_pose(&problem.id, None)?,
+            StartPose::Solver(name) => storage.load_pose(&problem.id, Some(name))?,
+            StartPose::Path(path) if path.is_dir() => {
+                let path = path.join(format!("{}.solution", problem.id));
+                if path.exists() {
+                    Some(storage::load_custom_solution(&path)?)
+                } else {
+                    None
+                }
+            }
+            StartPose::Path(path) => Some(storage::load_custom_solution(path)?),
+        };
+        let pose = match pose {
+            Some(pose) if pose.vertices.len() == problem.figure_for(&pose).vertices.len() => pose,
+            Some(_) => {
+                warn!(
+                    "Start pose for problem {} does not match the figure, using the default one",
+                    problem.id
+                );
+                problem.figure.get_default_pose()
+            }
+            None => {
+                if !matches!(self, StartPose::Default) {
+                    info!(
+                        "No start pose for problem {}, using the default one",
+                        problem.id
+                    );
+                }
+                problem.figure.get_default_pose()
+            }
+        };
+        problem.figure = problem.figure_for(&pose).into_owned();
+        Ok(pose)
+    }
+}
+
+// Outcome of a solver on a problem in a solve run.
+#[derive(Clone, Debug, Serialize)]
+pub struct RunResult {
+    pub problem: ProblemId,
+    pub solver: String,
+    pub dislikes: u64,
+    pub valid: bool,
+    // Seconds.
+    pub time: f64,
+    // Whether it replaced the best solution.
+    pub new_best: bool,
+    // Whether the pose collects the target bonus, None without one.
+    pub bonus_unlocked: Option<bool>,
+}
 
-pub fn run(solver_name: Option<&str>, id: Option<u32>) -> Result<()> {
+#[derive(Serialize)]
+pub struct RunReport {
+    // Seconds since the Unix epoch.
+    pub started_at: u64,
+    pub results: Vec<RunResult>,
+}
+
+// Runs the solvers on the problems, prints and stores the report.
+pub fn run(
+    storage: &Storage,
+    solver_name: Option<&str>,
+    id: Option<ProblemId>,
+    start: &StartPose,
+    bonus: Option<ProblemId>,
+) -> Result<RunReport> {
+    let started_at = unix_time();
     let mut solver_names = match solver_name {
         Some(name) => vec![name],
         None => SOLVERS.keys().map(|s| &s[..]).collect(),
     };
     solver_names.sort();
-    let ids = match id {
-        Some(id) => vec![id],
-        None => (1..=storage::get_problems_count()).collect(),
+    let ids = match &id {
+        Some(id) => vec![id.clone()],
+        None => storage.list_problems()?,
     };
-    ids.into_par_iter()
-        .map(|i| -> Result<()> {
+    let results = ids
+        .into_par_iter()
+        .map(|i| -> Result<Vec<RunResult>> {
             let mut stdout = String::new();
-            let problem = storage::load_problem(i)?;
-            let current_solution = storage::load_solution(i)?;
+            let mut results = Vec::new();
+            let mut problem = storage.load_problem(&i)?;
+            if let Some(target) = &bonus {
+                let unlock = problem
+                    .bonuses
+                    .iter()
+                    .find(|b| b.problem == *target)
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("Problem {} has no bonus for problem {}", i, target)
+                    })?;
+                problem.target_bonus = Some(unlock.position);
+            }
+            let current_solution = storage.load_solution(&i)?;
             // Only filter solved solutions in "Solve all" mode.
             if id.is_none()
                 && current_solution
@@ -26,19 +138,15 @@ pub fn run(solver_name: Option<&str>, id: Option<u32>) -> Result<()> {
                     .unwrap_or_default()
             {
                 warn!("Skipping problem {} as it's been solved optimally", i);
-                return Ok(());
+                return Ok(results);
             }
-            let mut best_dislikes = current_solution
-                .as_ref()
-                .map(|s| s.state.dislikes)
-                .unwrap_or(u64::MAX);
+            let initial_pose = start.load(storage, &mut problem)?;
             stdout += &format!("Problem {}\n", i);
             for &name in &solver_names {
-                let solver_solutions_path = storage::SOLUTIONS_PATH.join(name);
-                std::fs::create_dir_all(&solver_solutions_path)?;
+                storage.solver_solutions_path(name)?;
                 let solver = SOLVERS.get(name).unwrap();
                 let start = std::time::Instant::now();
-                let solution = solver.solve(problem.clone());
+                let solution = solver.solve(storage, problem.clone(), initial_pose.clone())?;
                 let time_taken = std::time::Instant::now() - start;
                 stdout += &format!(
                     "  {}: dislikes = {}, valid = {}, took {}.{}s\n",
@@ -48,20 +156,182 @@ pub fn run(solver_name: Option<&str>, id: Option<u32>) -> Result<()> {
                     time_taken.as_secs(),
                     time_taken.subsec_millis()
                 );
-                if solution.state.valid {
-                    storage::save_solution(&solution, Some(name))?;
-                    if best_dislikes > solution.state.dislikes {
+                let bonus_unlocked = problem.target_bonus_unlocked(&solution.pose);
+                if let Some(unlocked) = bonus_unlocked {
+                    stdout += &format!(
+                        "    bonus for problem {}: {}\n",
+                        bonus.as_ref().unwrap(),
+                        if unlocked { "unlocked" } else { "missed" }
+                    );
+                }
+                let replaced =
+                    record_solution(storage, name, solver.seed(), time_taken, &solution)?;
+                if let Some(best_dislikes) = replaced {
+                    stdout += &format!(
+                        "Replacing the current best solution ({} > {})\n",
+                        best_dislikes, solution.state.dislikes
+                    );
+                }
+                results.push(RunResult {
+                    problem: i.clone(),
+                    solver: name.to_owned(),
+                    dislikes: solution.state.dislikes,
+                    valid: solution.state.valid,
+                    time: time_taken.as_secs_f64(),
+                    new_best: replaced.is_some(),
+                    bonus_unlocked,
+                });
+            }
+            print!("{}", stdout);
+            Ok(results)
+        })
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+        .collect();
+    let report = RunReport {
+        started_at,
+        results,
+    };
+    print_report(&report);
+    let path = storage.save_run_report(started_at, &serde_json::to_string_pretty(&report)?)?;
+    info!("Saved the run report to '{}'", path.display());
+    Ok(report)
+}
+
+// Appends the solution to the history and, if it's valid, stores it as the one of the solver and
+// as the best one if it's better. Returns the dislikes of the replaced best solution.
+pub fn record_solution(
+    storage: &Storage,
+    name: &str,
+    seed: Option<u64>,
+    time_taken: std::time::Duration,
+    solution: &Solution,
+) -> Result<Option<u64>> {
+    storage.append_history(
+        &solution.id,
+        &HistoryEntry::new(name, seed, time_taken, solution),
+    )?;
+    if !solution.state.valid {
+        return Ok(None);
+    }
+    storage.solver_solutions_path(name)?;
+    storage.save_solution(solution, Some(name))?;
+    storage.save_best_if_better(solution)
+}
+
+pub fn print_report(report: &RunReport) {
+    println!(
+        "{:>7}  {:<20}  {:>8}  {:>5}  {:>8}  New best",
+        "Problem", "Solver", "Dislikes", "Valid", "Time"
+    );
+    for r in &report.results {
+        println!(
+            "{:>7}  {:<20}  {:>8}  {:>5}  {:>8.3}  {}",
+            r.problem,
+            r.solver,
+            r.dislikes,
+            r.valid,
+            r.time,
+            if r.new_best { "yes" } else { "" }
+        );
+    }
+    println!(
+        "{} runs, {} valid, {} new best solutions",
+        report.results.len(),
+        report.results.iter().filter(|r| r.valid).count(),
+        report.results.iter().filter(|r| r.new_best).count()
+    );
+}
+
+// Recomputes the metadata of every stored pose with the current validation and reports the
+// ones that differ, fixing them unless `dry_run`. Returns the number of discrepancies.
+pub fn reindex(storage: &Storage, dry_run: bool) -> Result<usize> {
+    let mut subfolders = vec![None];
+    subfolders.extend(storage.list_subfolders()?.into_iter().map(Some));
+    storage
+        .list_problems()?
+        .into_par_iter()
+        .map(|i| -> Result<usize> {
+            let mut stdout = String::new();
+            let mut discrepancies = 0;
+            let problem = storage.load_problem(&i)?;
+            for subfolder in &subfolders {
+                let subfolder = subfolder.as_deref();
+                let name = subfolder.unwrap_or("best");
+                let pose = match storage.load_pose(&i, subfolder) {
+                    Ok(Some(pose)) => pose,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        stdout += &format!("  {}: failed to read the pose: {}\n", name, e);
+                        discrepancies += 1;
+                        continue;
+                    }
+                };
+                let stored = storage.load_state(&i, subfolder).unwrap_or(None);
+                let figure_size = problem.figure_for(&pose).vertices.len();
+                let state = if pose.vertices.len() != figure_size {
+                    stdout += &format!(
+                        "  {}: pose has {} vertices, the figure has {}\n",
+                        name,
+                        pose.vertices.len(),
+                        figure_size
+                    );
+                    SolutionState {
+                        dislikes: u64::MAX,
+                        valid: false,
+                        optimal: false,
+                    }
+                } else {
+                    let dislikes = problem.dislikes(&pose);
+                    let valid = problem.validate(&pose);
+                    SolutionState {
+                        dislikes,
+                        valid,
+                        // Proven optimality can't be recomputed, keep it while the pose holds.
+                        optimal: valid
+                            && (dislikes == 0
+                                || stored
+                                    .as_ref()
+                                    .map(|s| s.optimal && s.dislikes == dislikes)
+                                    .unwrap_or(false)),
+                    }
+                };
+                let matches = stored
+                    .as_ref()
+                    .map(|s| {
+                        s.dislikes == state.dislikes
+                            && s.valid == state.valid
+                            && s.optimal == state.optimal
+                    })
+                    .unwrap_or(false);
+                if matches {
+                    continue;
+                }
+                discrepancies += 1;
+                match &stored {
+                    Some(s) => {
                         stdout += &format!(
-                            "Replacing the current best solution ({} > {})\n",
-                            best_dislikes, solution.state.dislikes
-                        );
-                        storage::save_solution(&solution, None)?;
-                        best_dislikes = solution.state.dislikes;
+                            "  {}: dislikes {} -> {}, valid {} -> {}, optimal {} -> {}\n",
+                            name,
+                            s.dislikes,
+                            state.dislikes,
+                            s.valid,
+                            state.valid,
+                            s.optimal,
+                            state.optimal
+                        )
                     }
+                    None => stdout += &format!("  {}: missing or unreadable metadata\n", name),
+                }
+                if !dry_run {
+                    storage.save_state(&i, subfolder, &state)?;
                 }
             }
-            print!("{}", stdout);
-            Ok(())
+            if !stdout.is_empty() {
+                print!("Problem {}\n{}", i, stdout);
+            }
+            Ok(discrepancies)
         })
-        .collect()
+        .sum()
 }
@@ -0,0 +1,421 @@
+use std::collections::HashMap;
+use std::io::Write;
+
+use rand::rngs::StdRng;
+
+use crate::common::*;
+use crate::problem::*;
+use crate::solver::Precalc;
+
+// Boolean encoding of a problem for external SAT/MaxSAT solvers.
+//
+// Every vertex gets one variable per lattice point inside the hole with exactly-one constraints
+// over them. Every edge is a table constraint: placing one end at a point implies the other end
+// is at one of the points at an allowed distance with the segment inside the hole. The dislikes
+// are soft clauses: for every hole vertex and every distance threshold, "some vertex is within
+// this distance" is rewarded with the gap to the next threshold.
+pub struct Encoding {
+    domains: Vec<Vec<Point>>,
+    // First variable (minus one) of every vertex domain.
+    offsets: Vec<usize>,
+    num_vars: usize,
+    hard: Vec<Vec<i64>>,
+    soft: Vec<(u64, Vec<i64>)>,
+    // Dislikes which are there regardless of the placement.
+    min_dislikes: u64,
+}
+
+impl Encoding {
+    pub fn new(problem: &mut Problem) -> Self {
+        let mut rng: StdRng = rand::SeedableRng::seed_from_u64(42);
+        let precalc = Precalc::new(problem, &mut rng);
+        let figure_size = problem.figure.vertices.len();
+
+        let (mn, mx) = problem.bounding_box();
+        let mut inside = Vec::new();
+        for x in mn.x..=mx.x {
+            for y in mn.y..=mx.y {
+                let p = Point { x, y };
+                if problem.contains_point(&p) {
+                    inside.push(p);
+                }
+            }
+        }
+        let index: HashMap<Point, usize> =
+            inside.iter().enumerate().map(|(i, &p)| (p, i)).collect();
+        let domains = vec![inside.clone(); figure_size];
+
+        let mut offsets = Vec::new();
+        let mut num_vars = 0;
+        for d in &domains {
+            offsets.push(num_vars);
+            num_vars += d.len();
+        }
+        let var = |v: usize, i: usize| (offsets[v] + i + 1) as i64;
+
+        let mut hard = Vec::new();
+        // Exactly one position per vertex, at-most-one with the sequential counter.
+        for (v, domain) in domains.iter().enumerate() {
+            let n = domain.len();
+            hard.push((0..n).map(|i| var(v, i)).collect());
+            if n < 2 {
+                continue;
+            }
+            let s = |i: usize| (num_vars + i + 1) as i64;
+            hard.push(vec![-var(v, 0), s(0)]);
+            for i in 1..n - 1 {
+                hard.push(vec![-var(v, i), s(i)]);
+                hard.push(vec![-s(i - 1), s(i)]);
+                hard.push(vec![-var(v, i), -s(i - 1)]);
+            }
+            hard.push(vec![-var(v, n - 1), -s(n - 2)]);
+            num_vars += n - 1;
+        }
+
+        // Allowed neighbour positions for every point, per edge.
+        for (e_id, e) in problem.figure.edges.iter().enumerate() {
+            let bounds = precalc.edge_bounds_precalc[e_id];
+            let max_d = std::cmp::min(bounds.1, precalc.delta_precalc.len() as i64 - 1);
+            let mut supports = vec![Vec::new(); inside.len()];
+            for (i, &p) in inside.iter().enumerate() {
+                for d in bounds.0..=max_d {
+                    for delta in &precalc.delta_precalc[d as usize] {
+                        let q = Point {
+                            x: p.x + delta.0,
+                            y: p.y + delta.1,
+                        };
+                        if let Some(&j) = index.get(&q) {
+                            if problem.contains_segment_exact((p, q)) {
+                                supports[i].push(j);
+                            }
+                        }
+                    }
+                }
+            }
+            // The relation is symmetric, so the same supports work for both ends.
+            for (i, js) in supports.iter().enumerate() {
+                let mut clause = vec![-var(e.v0, i)];
+                clause.extend(js.iter().map(|&j| var(e.v1, j)));
+                hard.push(clause);
+                let mut clause = vec![-var(e.v1, i)];
+                clause.extend(js.iter().map(|&j| var(e.v0, j)));
+                hard.push(clause);
+            }
+        }
+
+        // Dislikes: y_k means some vertex is within the k-th smallest distance of the hole vertex.
+        let mut soft = Vec::new();
+        let mut min_dislikes = 0;
+        for &h in &problem.hole {
+            let mut by_distance: Vec<(i64, Vec<i64>)> = Vec::new();
+            let mut points = inside
+                .iter()
+                .enumerate()
+                .map(|(i, &p)| (Figure::distance_squared_int(h, p), i))
+                .collect::<Vec<_>>();
+            points.sort();
+            for (d, i) in points {
+                if by_distance
+                    .last()
+                    .map(|(last, _)| *last != d)
+                    .unwrap_or(true)
+                {
+                    by_distance.push((d, Vec::new()));
+                }
+                let lits = &mut by_distance.last_mut().unwrap().1;
+                lits.extend((0..figure_size).map(|v| var(v, i)));
+            }
+            if by_distance.is_empty() {
+                continue;
+            }
+            min_dislikes += by_distance[0].0 as u64;
+            for k in 0..by_distance.len() - 1 {
+                num_vars += 1;
+                let y = num_vars as i64;
+                let mut clause = vec![-y];
+                clause.extend(by_distance[k].1.iter().cloned());
+                if k > 0 {
+                    clause.push(y - 1);
+                }
+                hard.push(clause);
+                soft.push(((by_distance[k + 1].0 - by_distance[k].0) as u64, vec![y]));
+            }
+        }
+
+        Encoding {
+            domains,
+            offsets,
+            num_vars,
+            hard,
+            soft,
+            min_dislikes,
+        }
+    }
+
+    // Only the hard constraints, any model is a valid pose.
+    pub fn write_cnf(&self, w: &mut impl Write) -> Result<()> {
+        writeln!(w, "c dislikes are ignored, any model is a valid pose")?;
+        writeln!(w, "p cnf {} {}", self.num_vars, self.hard.len())?;
+        for clause in &self.hard {
+            write_clause(w, clause)?;
+        }
+        Ok(())
+    }
+
+    // Hard constraints with the dislikes as soft clauses, the cost of a model plus
+    // `min_dislikes` is its dislikes.
+    pub fn write_wcnf(&self, w: &mut impl Write) -> Result<()> {
+        let top = self.soft.iter().map(|(weight, _)| weight).sum::<u64>() + 1;
+        writeln!(w, "c min_dislikes {}", self.min_dislikes)?;
+        writeln!(
+            w,
+            "p wcnf {} {} {}",
+            self.num_vars,
+            self.hard.len() + self.soft.len(),
+            top
+        )?;
+        for clause in &self.hard {
+            write!(w, "{} ", top)?;
+            write_clause(w, clause)?;
+        }
+        for (weight, clause) in &self.soft {
+            write!(w, "{} ", weight)?;
+            write_clause(w, clause)?;
+        }
+        Ok(())
+    }
+
+    // Reads the pose from the true variables of a model.
+    pub fn decode(&self, assignment: &[i64]) -> Result<Pose> {
+        let mut vertices = vec![None; self.domains.len()];
+        for &lit in assignment {
+            if lit <= 0 {
+                continue;
+            }
+            let var = (lit - 1) as usize;
+            let v = match self.offsets.iter().rposition(|&o| o <= var) {
+                Some(v) => v,
+                None => continue,
+            };
+            if let Some(&p) = self.domains[v].get(var - self.offsets[v]) {
+                if vertices[v].is_none() {
+                    vertices[v] = Some(p);
+                }
+            }
+        }
+        let vertices = vertices
+            .into_iter()
+            .enumerate()
+            .map(|(v, p)| p.ok_or_else(|| anyhow::anyhow!("Vertex {} is not assigned", v)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Pose {
+            vertices,
+            bonuses: vec![],
+            optimal: None,
+        })
+    }
+
+    pub fn solve_dpll(&self) -> Option<Vec<i64>> {
+        dpll(self.num_vars, &self.hard)
+    }
+}
+
+fn write_clause(w: &mut impl Write, clause: &[i64]) -> Result<()> {
+    for lit in clause {
+        write!(w, "{} ", lit)?;
+    }
+    writeln!(w, "0")?;
+    Ok(())
+}
+
+// Parses a model either as the solver competition output ("s ..." and "v ..." lines) or as
+// a plain list of literals.
+pub fn parse_assignment(data: &str) -> Result<Vec<i64>> {
+    let mut lits = Vec::new();
+    for line in data.lines() {
+        let line = line.trim();
+        if line.starts_with('s') {
+            if line.contains("UNSAT") {
+                return Err(anyhow::anyhow!("Solver reported no solution"));
+            }
+            continue;
+        }
+        let line = match line.strip_prefix('v') {
+            Some(rest) => rest,
+            None if line.starts_with('c') || line.starts_with('o') => continue,
+            None => line,
+        };
+        for token in line.split_whitespace() {
+            let lit: i64 = token.parse()?;
+            if lit != 0 {
+                lits.push(lit);
+            }
+        }
+    }
+    Ok(lits)
+}
+
+// Tiny DPLL with unit propagation, good enough for small problems without external solvers.
+// It backtracks over an explicit trail, the real problems have too many variables to recurse.
+pub fn dpll(num_vars: usize, clauses: &[Vec<i64>]) -> Option<Vec<i64>> {
+    let mut values = vec![0i8; num_vars + 1];
+    // Assigned variables in order, and for every decision its variable, the trail length before
+    // it and whether it's been flipped to false already.
+    let mut trail = Vec::new();
+    let mut decisions: Vec<(usize, usize, bool)> = Vec::new();
+    loop {
+        if propagate(clauses, &mut values, &mut trail) {
+            // The variables before the last decision are all assigned.
+            let start = decisions.last().map(|&(v, _, _)| v + 1).unwrap_or(1);
+            match (start..=num_vars).find(|&v| values[v] == 0) {
+                Some(v) => {
+                    decisions.push((v, trail.len(), false));
+                    values[v] = 1;
+                    trail.push(v);
+                }
+                None => {
+                    return Some(
+                        (1..=num_vars as i64)
+                            .map(|v| if values[v as usize] > 0 { v } else { -v })
+                            .collect(),
+                    )
+                }
+            }
+            continue;
+        }
+        // Conflict: undo up to the last decision not flipped yet and flip it.
+        loop {
+            let &mut (v, len, ref mut flipped) = decisions.last_mut()?;
+            for &u in &trail[len..] {
+                values[u] = 0;
+            }
+            trail.truncate(len);
+            if !*flipped {
+                *flipped = true;
+                values[v] = -1;
+                trail.push(v);
+                break;
+            }
+            decisions.pop();
+        }
+    }
+}
+
+fn lit_value(values: &[i8], lit: i64) -> i8 {
+    let value = values[lit.unsigned_abs() as usize];
+    if lit > 0 {
+        value
+    } else {
+        -value
+    }
+}
+
+// Assigns the last literal of the clauses with all the others false until there are none left,
+// false on a clause with all the literals false.
+fn propagate(clauses: &[Vec<i64>], values: &mut [i8], trail: &mut Vec<usize>) -> bool {
+    loop {
+        let mut changed = false;
+        for clause in clauses {
+            let mut unassigned = None;
+            let mut count = 0;
+            let mut satisfied = false;
+            for &lit in clause {
+                match lit_value(values, lit) {
+                    1 => {
+                        satisfied = true;
+                        break;
+                    }
+                    0 => {
+                        count += 1;
+                        unassigned = Some(lit);
+                    }
+                    _ => {}
+                }
+            }
+            if satisfied {
+                continue;
+            }
+            match (count, unassigned) {
+                (0, _) => return false,
+                (1, Some(lit)) => {
+                    let v = lit.unsigned_abs() as usize;
+                    values[v] = if lit > 0 { 1 } else { -1 };
+                    trail.push(v);
+                    changed = true;
+                }
+                _ => {}
+            }
+        }
+        if !changed {
+            return true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A right triangle in a 4x4 square hole, it fits in a few ways without any slack.
+    const PROBLEM: &str = r#"{
+        "hole": [[0, 0], [3, 0], [3, 3], [0, 3]],
+        "figure": {"vertices": [[0, 0], [2, 0], [0, 2]], "edges": [[0, 1], [1, 2], [2, 0]]},
+        "epsilon": 0,
+        "bonuses": []
+    }"#;
+
+    #[test]
+    fn dpll_model_decodes_to_valid_pose() {
+        let mut problem = Problem::from_json(1.into(), PROBLEM.as_bytes()).unwrap();
+        let encoding = Encoding::new(&mut problem);
+        let assignment = encoding.solve_dpll().expect("the problem is satisfiable");
+        let pose = encoding.decode(&assignment).unwrap();
+        assert_eq!(pose.vertices.len(), 3);
+        assert!(problem.validate(&pose));
+    }
+
+    #[test]
+    fn dpll_small_formulas() {
+        assert_eq!(dpll(1, &[vec![1], vec![-1]]), None);
+        assert_eq!(dpll(2, &[vec![1, 2], vec![-1]]), Some(vec![-1, 2]));
+    }
+
+    #[test]
+    fn dpll_many_free_variables() {
+        // Every variable is a decision, as deep as the old recursion could never go.
+        let n = 200_000;
+        let model = dpll(n, &[vec![-1, -(n as i64)]]).unwrap();
+        assert_eq!(model.len(), n);
+        assert_eq!(model[0], 1);
+        assert_eq!(model[n - 1], -(n as i64));
+    }
+
+    #[test]
+    fn dpll_backtracks_over_decisions() {
+        // Deciding 1 = true ends in a conflict, 1 = false forces 2 and 4.
+        let clauses = [vec![-1, 3], vec![-1, -3], vec![1, 2], vec![-2, 4]];
+        assert_eq!(dpll(4, &clauses), Some(vec![-1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn dpll_model_of_real_problem_is_valid() {
+        let data = include_str!("../problems/13.problem");
+        let mut problem = Problem::from_json(13.into(), data.as_bytes()).unwrap();
+        let encoding = Encoding::new(&mut problem);
+        let assignment = encoding.solve_dpll().expect("the problem has valid poses");
+        let pose = encoding.decode(&assignment).unwrap();
+        assert_eq!(pose.vertices.len(), problem.figure.vertices.len());
+        assert!(problem.validate(&pose));
+    }
+
+    #[test]
+    fn parse_assignment_formats() {
+        let competition = "c comment\ns SATISFIABLE\nv 1 -2 3\nv -4 0\n";
+        assert_eq!(parse_assignment(competition).unwrap(), vec![1, -2, 3, -4]);
+        let maxsat = "o 7\ns OPTIMUM FOUND\nv 1 2 -3 0\n";
+        assert_eq!(parse_assignment(maxsat).unwrap(), vec![1, 2, -3]);
+        assert_eq!(parse_assignment("1 -2 0\n").unwrap(), vec![1, -2]);
+        assert!(parse_assignment("s UNSATISFIABLE\n").is_err());
+        assert!(parse_assignment("v 1 x 0\n").is_err());
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::common::*;
+use crate::problem::ProblemId;
+use crate::runner::{self, RunReport, RunResult, StartPose};
+use crate::score;
+use crate::solver::SOLVERS;
+use crate::storage::Storage;
+
+pub struct ScheduleOptions {
+    pub solvers: Vec<String>,
+    // Problems to schedule, all of them if None.
+    pub ids: Option<Vec<ProblemId>>,
+    // Best dislikes among all the teams, the problems without one are assumed to have zero.
+    pub best_dislikes: HashMap<ProblemId, u64>,
+    // Budget of a job in the first round, it grows by `budget_growth` every round.
+    pub budget: Duration,
+    pub budget_growth: u32,
+    pub rounds: u32,
+    // Nothing new starts after that, and the budgets are cut to fit before it.
+    pub deadline: Option<Instant>,
+    pub start: StartPose,
+}
+
+impl Default for ScheduleOptions {
+    fn default() -> Self {
+        ScheduleOptions {
+            solvers: vec!["tree_search".to_owned()],
+            ids: None,
+            best_dislikes: HashMap::new(),
+            budget: Duration::from_secs(30),
+            budget_growth: 4,
+            rounds: 5,
+            deadline: None,
+            start: StartPose::Default,
+        }
+    }
+}
+
+// A solver on a problem.
+type Job = (ProblemId, String);
+
+// Runs the solvers on the problems in rounds, the ones with the largest expected score gain
+// first. The jobs that run out of the budget are queued again for the next round with a larger
+// one, the finished ones and the problems at the best known dislikes are dropped. A failed job
+// is only logged, so that it can run unattended.
+pub fn run(storage: &Storage, options: &ScheduleOptions) -> Result<()> {
+    let ids = match &options.ids {
+        Some(ids) => ids.clone(),
+        None => storage.list_problems()?,
+    };
+    let mut queue: Vec<Job> = ids
+        .iter()
+        .flat_map(|id| {
+            options
+                .solvers
+                .iter()
+                .map(move |name| (id.clone(), name.clone()))
+        })
+        .collect();
+    let mut budget = options.budget;
+    for round in 0..options.rounds {
+        if queue.is_empty() {
+            break;
+        }
+        if let Some(deadline) = options.deadline {
+            match deadline.checked_duration_since(Instant::now()) {
+                Some(left) => budget = budget.min(left),
+                None => break,
+            }
+        }
+
+        let gains = score::estimate(storage, &options.best_dislikes)?
+            .into_iter()
+            .map(|s| (s.id.clone(), s.potential_gain()))
+            .collect::<HashMap<_, _>>();
+        queue.retain(|(id, _)| gains.get(id).copied().unwrap_or(0) > 0);
+        queue.sort_by(|a, b| gains[&b.0].cmp(&gains[&a.0]).then_with(|| a.0.cmp(&b.0)));
+        println!(
+            "Round {}: {} jobs, {:.0}s each",
+            round + 1,
+            queue.len(),
+            budget.as_secs_f64()
+        );
+
+        let started_at = unix_time();
+        let (results, unfinished) = run_round(storage, options, queue, budget);
+        let report = RunReport {
+            started_at,
+            results,
+        };
+        runner::print_report(&report);
+        let data = serde_json::to_string_pretty(&report)?;
+        if let Err(e) = storage.save_run_report(started_at, &data) {
+            warn!("Failed to save the report of round {}: {}", round + 1, e);
+        }
+
+        queue = unfinished;
+        budget *= options.budget_growth;
+    }
+    if !queue.is_empty() {
+        println!("{} jobs left unfinished", queue.len());
+    }
+    Ok(())
+}
+
+// Runs the jobs in order on all the threads. Returns the results and the jobs out of budget.
+fn run_round(
+    storage: &Storage,
+    options: &ScheduleOptions,
+    jobs: Vec<Job>,
+    budget: Duration,
+) -> (Vec<RunResult>, Vec<Job>) {
+    let jobs = Mutex::new(jobs.into_iter().collect::<VecDeque<_>>());
+    let results = Mutex::new(Vec::new());
+    let unfinished = Mutex::new(Vec::new());
+    rayon::scope(|scope| {
+        for _ in 0..rayon::current_num_threads() {
+            scope.spawn(|_| loop {
+                let job = match jobs.lock().unwrap().pop_front() {
+                    Some(job) => job,
+                    None => break,
+                };
+                // The jobs started late in the round get only the time left.
+                let budget = match options.deadline {
+                    Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                        Some(left) => budget.min(left),
+                        None => {
+                            unfinished.lock().unwrap().push(job);
+                            continue;
+                        }
+                    },
+                    None => budget,
+                };
+                match run_job(storage, options, &job, budget) {
+                    Ok((result, finished)) => {
+                        if !finished {
+                            unfinished.lock().unwrap().push(job);
+                        }
+                        results.lock().unwrap().push(result);
+                    }
+                    Err(e) => warn!("Problem {}, {} failed: {}", job.0, job.1, e),
+                }
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap();
+    results.sort_by(|a, b| (&a.problem, &a.solver).cmp(&(&b.problem, &b.solver)));
+    (results, unfinished.into_inner().unwrap())
+}
+
+fn run_job(
+    storage: &Storage,
+    options: &ScheduleOptions,
+    (id, name): &Job,
+    budget: Duration,
+) -> Result<(RunResult, bool)> {
+    let mut problem = storage.load_problem(id)?;
+    let solver = SOLVERS
+        .get(name)
+        .ok_or_else(|| anyhow::anyhow!("Unknown solver '{}'", name))?;
+    let initial_pose = options.start.load(storage, &mut problem)?;
+    let start = Instant::now();
+    let (solution, finished) = solver.solve_with_budget(storage, problem, initial_pose, budget)?;
+    let time_taken = start.elapsed();
+    let replaced = runner::record_solution(storage, name, solver.seed(), time_taken, &solution)?;
+    info!(
+        "Problem {}, {}: dislikes = {}, valid = {}, finished = {}",
+        id, name, solution.state.dislikes, solution.state.valid, finished
+    );
+    Ok((
+        RunResult {
+            problem: id.clone(),
+            solver: name.clone(),
+            dislikes: solution.state.dislikes,
+            valid: solution.state.valid,
+            time: time_taken.as_secs_f64(),
+            new_best: replaced.is_some(),
+            bonus_unlocked: None,
+        },
+        finished,
+    ))
+}
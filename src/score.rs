@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::common::*;
+use crate::problem::ProblemId;
+use crate::storage::Storage;
+
+// Expected contest score of a problem from our solutions and the best known dislikes.
+pub struct ProblemScore {
+    pub id: ProblemId,
+    pub max_score: u64,
+    // Dislikes of the best valid stored solution.
+    pub local: Option<u64>,
+    // Dislikes of the last pose accepted by the portal.
+    pub server: Option<u64>,
+    // Best dislikes among all the teams, counting ours.
+    pub min_dislikes: u64,
+    pub local_score: u64,
+    pub server_score: u64,
+}
+
+impl ProblemScore {
+    // Score to get by submitting the stored solution.
+    pub fn submit_gain(&self) -> u64 {
+        self.local_score.saturating_sub(self.server_score)
+    }
+
+    // Score still missing if we matched the best known dislikes.
+    pub fn potential_gain(&self) -> u64 {
+        self.max_score
+            .saturating_sub(self.local_score.max(self.server_score))
+    }
+}
+
+// Best dislikes among all the teams, one "<problem id> <dislikes>" pair per line as copied from
+// the portal. Empty lines and the ones starting with '#' are ignored.
+pub fn load_best_dislikes(path: &Path) -> Result<HashMap<ProblemId, u64>> {
+    let mut best = HashMap::new();
+    for (n, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parts = line.split_whitespace().collect::<Vec<_>>();
+        match parts[..] {
+            [id, dislikes] => {
+                best.insert(id.parse()?, dislikes.parse()?);
+            }
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "{}:{}: expected '<problem id> <dislikes>'",
+                    path.display(),
+                    n + 1
+                ))
+            }
+        }
+    }
+    Ok(best)
+}
+
+// Scores of all the problems. Without the known best dislikes of a problem, someone is assumed
+// to have zero.
+pub fn estimate(storage: &Storage, best: &HashMap<ProblemId, u64>) -> Result<Vec<ProblemScore>> {
+    let mut scores = Vec::new();
+    for id in storage.list_problems()? {
+        let problem = storage.load_problem(&id)?;
+        let local = storage
+            .load_solution(&id)?
+            .filter(|s| s.state.valid)
+            .map(|s| s.state.dislikes);
+        let server = Some(storage.load_server_state(&id)?.dislikes).filter(|&d| d != u64::MAX);
+        let min_dislikes = best
+            .get(&id)
+            .copied()
+            .unwrap_or(0)
+            .min(local.unwrap_or(u64::MAX))
+            .min(server.unwrap_or(u64::MAX));
+        let score = |d: Option<u64>| d.map(|d| problem.score(d, min_dislikes)).unwrap_or(0);
+        scores.push(ProblemScore {
+            id,
+            max_score: problem.score(min_dislikes, min_dislikes),
+            local,
+            server,
+            min_dislikes,
+            local_score: score(local),
+            server_score: score(server),
+        });
+    }
+    Ok(scores)
+}
+
+pub fn print_report(scores: &[ProblemScore]) {
+    let dislikes = |d: Option<u64>| match d {
+        Some(d) => d.to_string(),
+        None => "-".to_owned(),
+    };
+    // The problems where the compute pays off the most go first.
+    let mut sorted = scores.iter().collect::<Vec<_>>();
+    sorted.sort_by(|a, b| {
+        b.potential_gain()
+            .cmp(&a.potential_gain())
+            .then_with(|| a.id.cmp(&b.id))
+    });
+
+    println!(
+        "{:>7}  {:>8}  {:>8}  {:>8}  {:>6}  {:>6}  {:>6}  {:>6}",
+        "Problem", "Local", "Server", "Best", "Max", "Score", "Submit", "Gain"
+    );
+    for s in sorted {
+        println!(
+            "{:>7}  {:>8}  {:>8}  {:>8}  {:>6}  {:>6}  {:>6}  {:>6}",
+            s.id,
+            dislikes(s.local),
+            dislikes(s.server),
+            s.min_dislikes,
+            s.max_score,
+            s.server_score,
+            s.submit_gain(),
+            s.potential_gain()
+        );
+    }
+    let total = |f: fn(&ProblemScore) -> u64| scores.iter().map(f).sum::<u64>();
+    println!(
+        "Score on the server: {}, after submitting: {}, maximum: {}",
+        total(|s| s.server_score),
+        total(|s| s.local_score.max(s.server_score)),
+        total(|s| s.max_score)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::problem::Problem;
+
+    // 3 vertices, 3 edges and 4 hole vertices: the maximum is 1000 * log2(3 * 3 * 4 / 6), about
+    // 2584.96.
+    const PROBLEM: &str = r#"{
+        "hole": [[0, 0], [3, 0], [3, 3], [0, 3]],
+        "figure": {"vertices": [[0, 0], [2, 0], [0, 2]], "edges": [[0, 1], [1, 2], [2, 0]]},
+        "epsilon": 0,
+        "bonuses": []
+    }"#;
+
+    #[test]
+    fn score_formula() {
+        let problem = Problem::from_json(1.into(), PROBLEM.as_bytes()).unwrap();
+        assert_eq!(problem.score(0, 0), 2585);
+        // sqrt(1 / 4) of the maximum, 1292.48 rounded up.
+        assert_eq!(problem.score(3, 0), 1293);
+        // sqrt(3 / 9) of the maximum, 1492.43 rounded up.
+        assert_eq!(problem.score(8, 2), 1493);
+    }
+
+    #[test]
+    fn gains_count_the_better_of_the_local_and_server_scores() {
+        let score = |local_score, server_score| ProblemScore {
+            id: 1.into(),
+            max_score: 2585,
+            local: None,
+            server: None,
+            min_dislikes: 0,
+            local_score,
+            server_score,
+        };
+        assert_eq!(score(1293, 1000).submit_gain(), 293);
+        assert_eq!(score(1293, 1000).potential_gain(), 1292);
+        assert_eq!(score(1000, 1293).submit_gain(), 0);
+        assert_eq!(score(1000, 1293).potential_gain(), 1292);
+        assert_eq!(score(2585, 0).potential_gain(), 0);
+    }
+}
@@ -6,10 +6,11 @@ use std::{cell::RefCell, rc::Rc};
 
 use crate::common::*;
 use crate::problem::{Figure, Point, Pose, Problem};
+use crate::storage::Storage;
 use rand::rngs::StdRng;
 use rand::Rng;
 
-use super::Solver;
+use super::{seed, Solver};
 
 const INNER_IT: usize = 10000;
 const START_T: f64 = 20.0;
@@ -29,6 +30,11 @@ pub struct ViolationSummary {
     vertex_violation: f64,
     deform_violation: f64,
     intersect_violations: f64,
+    // Distance from the target bonus, if the problem has one.
+    bonus_violation: f64,
+    // With GLOBALIST the deform violation is the total deformation, only the part above the
+    // budget counts.
+    deform_budget: Option<f64>,
 }
 
 impl Display for ViolationSummary {
@@ -38,11 +44,12 @@ impl Display for ViolationSummary {
         // into a buffer (the first argument)
         write!(
             f,
-            "(d: {}, vertex_v: {:.3}, deform: {:.3}, intersect: {:.3}, energy: {:.3})",
+            "(d: {}, vertex_v: {:.3}, deform: {:.3}, intersect: {:.3}, bonus: {:.3}, energy: {:.3})",
             self.dislikes,
             self.vertex_violation,
             self.deform_violation,
             self.intersect_violations,
+            self.bonus_violation,
             self.energy()
         )
     }
@@ -57,11 +64,20 @@ pub struct ViolationState {
 }
 
 impl ViolationSummary {
+    fn deform_overrun(&self) -> f64 {
+        match self.deform_budget {
+            // Deformation ratios, weighted to be comparable with the squared lengths.
+            Some(budget) => 1000.0 * (self.deform_violation - budget).max(0.0),
+            None => self.deform_violation,
+        }
+    }
+
     fn energy(&self) -> f64 {
         self.dislikes as f64
             + 100.0 * self.vertex_violation
-            + 100.0 * self.deform_violation
+            + 100.0 * self.deform_overrun()
             + 1000.0 * self.intersect_violations
+            + 100.0 * self.bonus_violation
     }
 }
 
@@ -70,12 +86,13 @@ impl Solver for AnnealingSolver {
         &self,
         problem: Problem,
         pose: Rc<RefCell<Pose>>,
+        _storage: &Storage,
     ) -> generator::LocalGenerator<'a, (), Rc<RefCell<Pose>>> {
         generator::Gn::new_scoped_local(move |mut s| {
             // Show initial state to the visualizer.
             s.yield_(pose.clone());
 
-            let mut rng: StdRng = rand::SeedableRng::seed_from_u64(42);
+            let mut rng: StdRng = rand::SeedableRng::seed_from_u64(seed());
 
             // Compute how much we violate the state with current pose.
             let mut cur_violation_state = compute_violation_state(&pose.borrow(), &problem);
@@ -184,33 +201,31 @@ impl Solver for AnnealingSolver {
                         // Compute dislikes.
                         pose.borrow_mut().vertices[vertex_index] = new_pos;
                         let dislikes = problem.dislikes(&pose.borrow());
+                        let bonus_violation = problem.target_bonus_distance(&pose.borrow());
                         pose.borrow_mut().vertices[vertex_index] = cur_pos;
                         // Vertex violation.
-                        let vertex_violation = problem.min_distance_to(new_pos);
+                        let vertex_violation = vertex_violation(vertex_index, new_pos, &problem);
                         let delta_vertex_violation =
                             vertex_violation - cur_violation_state.vertex_violations[vertex_index];
                         // Edge deformation violation.
                         // TODO: Take the previous violation from map.
-                        let cur_deform_violation = vertex_edges_deform_violation(
-                            vertex_index,
-                            cur_pos,
-                            &pose,
-                            &problem.figure,
-                        );
-                        let new_deform_violation = vertex_edges_deform_violation(
-                            vertex_index,
-                            new_pos,
-                            &pose,
-                            &problem.figure,
-                        );
+                        let cur_deform_violation =
+                            vertex_edges_deform_violation(vertex_index, cur_pos, &pose, &problem);
+                        let new_deform_violation =
+                            vertex_edges_deform_violation(vertex_index, new_pos, &pose, &problem);
                         let delta_deform_violation = new_deform_violation - cur_deform_violation;
 
                         // Edge intersection violation.
                         let mut new_edge_intersect_violations = Vec::new();
                         let mut delta_intersect_violation = 0.0;
                         for (edge_index, dst) in &problem.figure.vertex_edges[vertex_index] {
-                            let new_edge_intersect_violation =
-                                problem.edge_intersections(new_pos, pose.borrow().vertices[*dst]);
+                            let new_edge_intersect_violation = edge_intersect_violation(
+                                vertex_index,
+                                *dst,
+                                new_pos,
+                                pose.borrow().vertices[*dst],
+                                &problem,
+                            );
                             new_edge_intersect_violations
                                 .push((*edge_index, new_edge_intersect_violation));
                             delta_intersect_violation += new_edge_intersect_violation
@@ -225,6 +240,8 @@ impl Solver for AnnealingSolver {
                                 + delta_deform_violation,
                             intersect_violations: cur_violation_state.summary.intersect_violations
                                 + delta_intersect_violation,
+                            bonus_violation,
+                            deform_budget: cur_violation_state.summary.deform_budget,
                         };
 
                         let cur_energy = cur_violation_state.summary.energy();
@@ -265,25 +282,51 @@ impl Solver for AnnealingSolver {
             done!()
         })
     }
+
+    fn seed(&self) -> Option<u64> {
+        Some(seed())
+    }
 }
 
 fn accept_energy(prev_energy: f64, new_energy: f64, temperature: f64, rng: &mut StdRng) -> bool {
     return ((prev_energy - new_energy) / temperature).exp() > rng.gen();
 }
 
+// Distance from the hole, none for the WALLHACK vertex.
+fn vertex_violation(vertex_index: usize, vertex_position: Point, problem: &Problem) -> f64 {
+    if problem.wallhack == Some(vertex_index) {
+        return 0.0;
+    }
+    problem.min_distance_to(vertex_position)
+}
+
+// Edges of the WALLHACK vertex can go outside of the hole.
+fn edge_intersect_violation(
+    src: usize,
+    dst: usize,
+    src_pos: Point,
+    dst_pos: Point,
+    problem: &Problem,
+) -> f64 {
+    if problem.wallhack == Some(src) || problem.wallhack == Some(dst) {
+        return 0.0;
+    }
+    problem.edge_intersections(src_pos, dst_pos)
+}
+
 fn vertex_edges_deform_violation(
     vertex_index: usize,
     vertex_position: Point,
     pose: &Rc<RefCell<Pose>>,
-    figure: &Figure,
+    problem: &Problem,
 ) -> f64 {
     let mut total_violation = 0.0;
-    for (edge_index, dst) in &figure.vertex_edges[vertex_index] {
+    for (edge_index, dst) in &problem.figure.vertex_edges[vertex_index] {
         total_violation += edge_deform_violation(
             *edge_index,
             vertex_position,
             pose.borrow().vertices[*dst],
-            figure,
+            problem,
         );
     }
     return total_violation;
@@ -293,10 +336,17 @@ fn edge_deform_violation(
     edge_index: usize,
     src_pos: Point,
     dst_pos: Point,
-    figure: &Figure,
+    problem: &Problem,
 ) -> f64 {
+    // The SUPERFLEX edge can have any length.
+    if problem.superflex == Some(edge_index) {
+        return 0.0;
+    }
     let new_distance = Figure::distance_squared(src_pos, dst_pos);
-    let bounds = figure.edge_len2_bounds(edge_index);
+    if problem.globalist {
+        return (new_distance / problem.figure.edges[edge_index].len2 - 1.0).abs();
+    }
+    let bounds = problem.figure.edge_len2_bounds(edge_index);
     if new_distance < bounds.0 {
         return bounds.0 - new_distance;
     } else if new_distance > bounds.1 {
@@ -329,7 +379,7 @@ fn compute_violation_state(pose: &Pose, problem: &Problem) -> ViolationState {
     let mut total_vertex_violation = 0.0;
     let mut vertex_violations = vec![0.0; pose.vertices.len()];
     for (v_index, vertex) in pose.vertices.iter().enumerate() {
-        vertex_violations[v_index] = problem.min_distance_to(*vertex);
+        vertex_violations[v_index] = vertex_violation(v_index, *vertex, problem);
         total_vertex_violation += vertex_violations[v_index];
     }
 
@@ -343,12 +393,17 @@ fn compute_violation_state(pose: &Pose, problem: &Problem) -> ViolationState {
             e_index,
             pose.vertices[edge.v0],
             pose.vertices[edge.v1],
-            &problem.figure,
+            problem,
         );
         total_deform_violation += deform_violations[e_index];
 
-        intersect_violations[e_index] =
-            problem.edge_intersections(pose.vertices[edge.v0], pose.vertices[edge.v1]);
+        intersect_violations[e_index] = edge_intersect_violation(
+            edge.v0,
+            edge.v1,
+            pose.vertices[edge.v0],
+            pose.vertices[edge.v1],
+            problem,
+        );
         total_intersect_violation += intersect_violations[e_index];
     }
 
@@ -358,6 +413,12 @@ fn compute_violation_state(pose: &Pose, problem: &Problem) -> ViolationState {
             vertex_violation: total_vertex_violation,
             deform_violation: total_deform_violation,
             intersect_violations: total_intersect_violation,
+            bonus_violation: problem.target_bonus_distance(pose),
+            deform_budget: if problem.globalist {
+                Some(problem.figure.edges.len() as f64 * problem.figure.epsilon)
+            } else {
+                None
+            },
         },
         vertex_violations,
         deform_violations,
@@ -0,0 +1,363 @@
+use ordered_float::NotNan;
+use rand::rngs::StdRng;
+use std::{cell::RefCell, rc::Rc};
+
+use crate::common::*;
+use crate::problem::*;
+use crate::storage::Storage;
+
+use super::tree_search::{placement_order, Precalc};
+use super::{seed, Solver};
+
+// Breadth-first variant of the tree search: places vertices in the same order, but keeps only
+// the best `beam_width` partial placements at every depth. The pass is repeated with a doubled
+// width until the timeout, so it gives an answer early and keeps improving it.
+pub struct BeamSearchSolver {
+    pub beam_width: usize,
+    pub timeout: Option<std::time::Duration>,
+}
+
+impl Default for BeamSearchSolver {
+    fn default() -> Self {
+        BeamSearchSolver {
+            beam_width: 64,
+            timeout: Some(std::time::Duration::from_secs(60)),
+        }
+    }
+}
+
+// Weight of the tight domains penalty, a vertex with a single option costs that many dislikes.
+const DOMAIN_WEIGHT: f64 = 100.0;
+// Domains are only counted up to this size, larger ones are loose enough.
+const MAX_DOMAIN_COUNT: u32 = 1000;
+
+struct Partial {
+    pose: Pose,
+    // GLOBALIST deformation of the edges between the placed vertices.
+    budget_used: f64,
+    // Number of positions left for the unplaced vertices with placed neighbours, 0 if unknown.
+    domains: Vec<u32>,
+}
+
+struct Candidate {
+    parent: usize,
+    position: Point,
+    budget_used: f64,
+    domains: Vec<(usize, u32)>,
+    score: NotNan<f64>,
+}
+
+impl Solver for BeamSearchSolver {
+    fn solve_gen<'a>(
+        &self,
+        mut problem: Problem,
+        pose: Rc<RefCell<Pose>>,
+        _storage: &Storage,
+    ) -> generator::LocalGenerator<'a, (), Rc<RefCell<Pose>>> {
+        let beam_width = self.beam_width;
+        let timeout = self.timeout;
+        let mut rng: StdRng = rand::SeedableRng::seed_from_u64(seed());
+
+        generator::Gn::new_scoped_local(move |mut s| {
+            s.yield_(pose.clone());
+
+            let deadline = timeout.map(|t| std::time::Instant::now() + t);
+            let precalc = Precalc::new(&mut problem, &mut rng);
+            let order = placement_order(&problem);
+            let search = BeamSearch::new(&problem, &precalc, order);
+
+            let mut best_dislikes = None;
+            let mut width = beam_width;
+            loop {
+                info!("Beam search pass with width {}", width);
+                let found = search.run(&pose.borrow(), width, deadline);
+                if let Some(found) = found {
+                    let dislikes = problem.dislikes(&found);
+                    if best_dislikes.map(|d| dislikes < d).unwrap_or(true) {
+                        info!("Found better placement, dislikes: {}", dislikes);
+                        best_dislikes = Some(dislikes);
+                        pose.replace(found);
+                        s.yield_(pose.clone());
+                    }
+                }
+                if best_dislikes == Some(0)
+                    || deadline
+                        .map(|d| std::time::Instant::now() > d)
+                        .unwrap_or(width >= beam_width * 64)
+                {
+                    break;
+                }
+                width *= 2;
+            }
+
+            done!();
+        })
+    }
+
+    fn seed(&self) -> Option<u64> {
+        Some(seed())
+    }
+}
+
+struct BeamSearch<'p> {
+    problem: &'p Problem,
+    precalc: &'p Precalc,
+    order: Vec<usize>,
+    v_in_order: Vec<usize>,
+}
+
+impl<'p> BeamSearch<'p> {
+    fn new(problem: &'p Problem, precalc: &'p Precalc, order: Vec<usize>) -> Self {
+        let mut v_in_order = vec![usize::MAX; problem.figure.vertices.len()];
+        for (i, &v) in order.iter().enumerate() {
+            v_in_order[v] = i;
+        }
+        BeamSearch {
+            problem,
+            precalc,
+            order,
+            v_in_order,
+        }
+    }
+
+    fn run(
+        &self,
+        start: &Pose,
+        width: usize,
+        deadline: Option<std::time::Instant>,
+    ) -> Option<Pose> {
+        let figure = &self.problem.figure;
+        // Precalc lets every edge use the whole GLOBALIST budget, the placements share it.
+        let budget = figure.edges.len() as f64 * figure.epsilon;
+        let mut beam = vec![Partial {
+            pose: start.clone(),
+            budget_used: 0.0,
+            domains: vec![0; figure.vertices.len()],
+        }];
+
+        for index in 0..self.order.len() {
+            if deadline
+                .map(|d| std::time::Instant::now() > d)
+                .unwrap_or(false)
+            {
+                return None;
+            }
+            let v = self.order[index];
+            let reach = self.reach(index);
+
+            let mut candidates = Vec::new();
+            for (parent, partial) in beam.iter().enumerate() {
+                // Dislikes bound from the vertices placed before `v`.
+                let base = self
+                    .problem
+                    .hole
+                    .iter()
+                    .map(|&h| {
+                        self.order[..index]
+                            .iter()
+                            .map(|&u| bound(h, partial.pose.vertices[u], reach[u]))
+                            .fold(f64::MAX, f64::min)
+                    })
+                    .collect::<Vec<_>>();
+
+                let mut pose = partial.pose.clone();
+                for position in self.positions(v, index, &pose) {
+                    pose.vertices[v] = position;
+                    let mut budget_used = partial.budget_used;
+                    if self.problem.globalist {
+                        for &(e_id, u) in &figure.vertex_edges[v] {
+                            if self.v_in_order[u] < index {
+                                budget_used += figure.edge_deformation(e_id, &pose);
+                            }
+                        }
+                        if budget_used > budget {
+                            continue;
+                        }
+                    }
+                    let mut domains = Vec::new();
+                    let mut dead_end = false;
+                    for &(_, w) in &figure.vertex_edges[v] {
+                        if self.v_in_order[w] > index {
+                            let size = self.positions(w, index + 1, &pose).len() as u32;
+                            if size == 0 {
+                                dead_end = true;
+                                break;
+                            }
+                            domains.push((w, size));
+                        }
+                    }
+                    if dead_end {
+                        continue;
+                    }
+
+                    let dislikes_bound: f64 = self
+                        .problem
+                        .hole
+                        .iter()
+                        .zip(base.iter())
+                        .map(|(&h, &b)| b.min(bound(h, position, reach[v])))
+                        .sum();
+                    let mut tightness = 0.0;
+                    for w in &self.order[index + 1..] {
+                        let size = domains
+                            .iter()
+                            .find(|(u, _)| u == w)
+                            .map(|&(_, size)| size)
+                            .unwrap_or(partial.domains[*w]);
+                        if size > 0 {
+                            tightness += 1.0 / size as f64;
+                        }
+                    }
+                    candidates.push(Candidate {
+                        parent,
+                        position,
+                        budget_used,
+                        domains,
+                        score: NotNan::new(dislikes_bound + DOMAIN_WEIGHT * tightness).unwrap(),
+                    });
+                }
+            }
+            candidates.sort_by_key(|c| c.score);
+
+            let mut next_beam = Vec::new();
+            for candidate in candidates {
+                if next_beam.len() >= width {
+                    break;
+                }
+                let parent = &beam[candidate.parent];
+                let fits = figure.vertex_edges[v]
+                    .iter()
+                    .filter(|&&(_, u)| self.v_in_order[u] < index)
+                    .all(|&(_, u)| {
+                        self.problem
+                            .contains_segment_exact((candidate.position, parent.pose.vertices[u]))
+                    });
+                if !fits {
+                    continue;
+                }
+                let mut pose = parent.pose.clone();
+                pose.vertices[v] = candidate.position;
+                let mut domains = parent.domains.clone();
+                domains[v] = 0;
+                for (w, size) in candidate.domains {
+                    domains[w] = size;
+                }
+                next_beam.push(Partial {
+                    pose,
+                    budget_used: candidate.budget_used,
+                    domains,
+                });
+            }
+            debug!("Depth {}: {} partial placements", index, next_beam.len());
+            if next_beam.is_empty() {
+                info!("Beam died out at depth {} of {}", index, self.order.len());
+                return None;
+            }
+            beam = next_beam;
+        }
+
+        beam.into_iter()
+            .map(|p| p.pose)
+            .filter(|p| self.problem.contains(p))
+            .min_by_key(|p| self.problem.dislikes(p))
+    }
+
+    // Positions of `v` inside the hole consistent with the edges to the first `placed` vertices
+    // of the order, up to `MAX_DOMAIN_COUNT` of them.
+    fn positions(&self, v: usize, placed: usize, pose: &Pose) -> Vec<Point> {
+        let figure = &self.problem.figure;
+        let neighbours = figure.vertex_edges[v]
+            .iter()
+            .filter(|&&(_, u)| self.v_in_order[u] < placed)
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut positions = Vec::new();
+        let (mn, mx) = self.problem.bounding_box();
+        match neighbours.first() {
+            None => {
+                for x in mn.x..=mx.x {
+                    for y in mn.y..=mx.y {
+                        let p = Point { x, y };
+                        if self.problem.contains_point(&p) {
+                            positions.push(p);
+                        }
+                    }
+                }
+            }
+            Some(&(e_id, u)) => {
+                let pu = pose.vertices[u];
+                let bounds = self.precalc.edge_bounds_precalc[e_id];
+                let max_d = std::cmp::min(bounds.1, self.precalc.delta_precalc.len() as i64 - 1);
+                for d in bounds.0..=max_d {
+                    for delta in self.precalc.delta_precalc[d as usize].iter() {
+                        let p = Point {
+                            x: pu.x + delta.0,
+                            y: pu.y + delta.1,
+                        };
+                        if !self.problem.contains_point(&p) {
+                            continue;
+                        }
+                        let fits = neighbours[1..].iter().all(|&(e_id, w)| {
+                            let d = Figure::distance_squared_int(p, pose.vertices[w]);
+                            let bounds = self.precalc.edge_bounds_precalc[e_id];
+                            bounds.0 <= d && d <= bounds.1
+                        });
+                        if fits {
+                            positions.push(p);
+                            if positions.len() as u32 >= MAX_DOMAIN_COUNT {
+                                return positions;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        positions
+    }
+
+    // For every vertex placed by `index` (inclusive), the furthest distance an unplaced vertex
+    // closest to it in the graph can reach. Dijkstra from the placed set over max edge lengths.
+    fn reach(&self, index: usize) -> Vec<f64> {
+        let figure = &self.problem.figure;
+        let n = figure.vertices.len();
+        let mut dist = vec![f64::MAX; n];
+        let mut anchor = vec![usize::MAX; n];
+        let mut heap = std::collections::BinaryHeap::new();
+        for &u in &self.order[..=index] {
+            dist[u] = 0.0;
+            anchor[u] = u;
+            heap.push((std::cmp::Reverse(NotNan::new(0.0).unwrap()), u));
+        }
+        while let Some((std::cmp::Reverse(d), u)) = heap.pop() {
+            if d.into_inner() > dist[u] {
+                continue;
+            }
+            for &(e_id, w) in &figure.vertex_edges[u] {
+                if self.v_in_order[w] <= index {
+                    continue;
+                }
+                let len = (self.precalc.edge_bounds_precalc[e_id].1 as f64).sqrt();
+                if dist[u] + len < dist[w] {
+                    dist[w] = dist[u] + len;
+                    anchor[w] = anchor[u];
+                    heap.push((std::cmp::Reverse(NotNan::new(dist[w]).unwrap()), w));
+                }
+            }
+        }
+
+        let mut reach = vec![0.0f64; n];
+        for w in 0..n {
+            if anchor[w] != usize::MAX && anchor[w] != w {
+                reach[anchor[w]] = reach[anchor[w]].max(dist[w]);
+            }
+        }
+        reach
+    }
+}
+
+// Lower bound on the dislikes of the hole vertex `h` from a vertex at `p` and the unplaced
+// vertices within `reach` of it.
+fn bound(h: Point, p: Point, reach: f64) -> f64 {
+    let d = (Figure::distance_squared(h, p).sqrt() - reach).max(0.0);
+    d * d
+}
@@ -1,6 +1,7 @@
 use std::{cell::RefCell, rc::Rc};
 
 use crate::problem::{Pose, Problem};
+use crate::storage::Storage;
 
 use super::Solver;
 
@@ -15,9 +16,10 @@ impl<S1: Solver + Default, S2: Solver + Default> Solver for Cons<S1, S2> {
         &self,
         problem: Problem,
         pose: Rc<RefCell<Pose>>,
+        storage: &Storage,
     ) -> generator::LocalGenerator<'a, (), Rc<RefCell<Pose>>> {
-        let gen1 = self.s1.solve_gen(problem.clone(), pose.clone());
-        let gen2 = self.s2.solve_gen(problem, pose);
+        let gen1 = self.s1.solve_gen(problem.clone(), pose.clone(), storage);
+        let gen2 = self.s2.solve_gen(problem, pose, storage);
         generator::Gn::new_scoped_local(move |mut s| {
             for pose in gen1 {
                 s.yield_(pose);
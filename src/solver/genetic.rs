@@ -0,0 +1,279 @@
+use rand::rngs::StdRng;
+use rand::Rng;
+use rayon::prelude::*;
+use std::collections::VecDeque;
+use std::{cell::RefCell, rc::Rc};
+
+use crate::common::*;
+use crate::problem::*;
+use crate::storage::Storage;
+use crate::transform::Transform;
+
+use super::{seed, Solver, SOLVERS};
+
+// Population based search over valid poses: children take a connected part of the figure
+// from one parent and the rest from another, and are then repaired and selected on dislikes.
+pub struct GeneticSolver {
+    // Solvers run to seed the population in addition to the stored solutions.
+    pub seed_solvers: Vec<&'static str>,
+    pub population_size: usize,
+    pub children: usize,
+    pub generations: usize,
+}
+
+impl Default for GeneticSolver {
+    fn default() -> Self {
+        GeneticSolver {
+            seed_solvers: vec!["annealing"],
+            population_size: 32,
+            children: 64,
+            generations: 100,
+        }
+    }
+}
+
+// How many times to run the edge fixing over the broken vertices of a child.
+const REPAIR_ROUNDS: usize = 3;
+// Half size of the region the edge fixing searches for a better vertex position.
+const REPAIR_RADIUS: i64 = 3;
+const TOURNAMENT_SIZE: usize = 3;
+
+struct Individual {
+    pose: Pose,
+    dislikes: u64,
+}
+
+impl Solver for GeneticSolver {
+    fn solve_gen<'a>(
+        &self,
+        problem: Problem,
+        pose: Rc<RefCell<Pose>>,
+        storage: &Storage,
+    ) -> generator::LocalGenerator<'a, (), Rc<RefCell<Pose>>> {
+        let seed_solvers = self.seed_solvers.clone();
+        let population_size = self.population_size;
+        let children = self.children;
+        let generations = self.generations;
+        let storage = storage.clone();
+        let mut rng: StdRng = rand::SeedableRng::seed_from_u64(seed());
+
+        generator::Gn::new_scoped_local(move |mut s| {
+            s.yield_(pose.clone());
+
+            let mut candidates = vec![pose.borrow().clone()];
+            if let Ok(Some(best)) = storage.load_pose(&problem.id, None) {
+                candidates.push(best);
+            }
+            // Every subfolder, the manual and imported poses included.
+            for name in storage.list_subfolders().unwrap_or_default() {
+                if let Ok(Some(p)) = storage.load_pose(&problem.id, Some(&name)) {
+                    candidates.push(p);
+                }
+            }
+            for name in &seed_solvers {
+                info!("Seeding the population with {}", name);
+                let seed = SOLVERS[*name]
+                    .solve_gen(
+                        problem.clone(),
+                        Rc::new(RefCell::new(problem.figure.get_default_pose())),
+                        &storage,
+                    )
+                    .last()
+                    .unwrap()
+                    .take();
+                candidates.push(seed);
+            }
+
+            let mut population = Vec::new();
+            for candidate in candidates {
+                add_individual(&problem, &mut population, candidate);
+            }
+            population.sort_by_key(|i| i.dislikes);
+            if population.len() < 2 {
+                warn!(
+                    "Not enough valid poses to breed ({}), need at least 2",
+                    population.len()
+                );
+                if let Some(best) = population.into_iter().next() {
+                    s.yield_(Rc::new(RefCell::new(best.pose)));
+                }
+                done!();
+            }
+            info!("Initial population of {} poses", population.len());
+            let mut best_dislikes = population[0].dislikes;
+            pose.replace(population[0].pose.clone());
+            s.yield_(pose.clone());
+
+            for generation in 0..generations {
+                if best_dislikes == 0 {
+                    break;
+                }
+                let offspring = (0..children)
+                    .map(|_| {
+                        let a = tournament(&population, &mut rng);
+                        let b = tournament(&population, &mut rng);
+                        crossover(&problem, &population[a].pose, &population[b].pose, &mut rng)
+                    })
+                    .collect::<Vec<_>>();
+                let offspring = offspring
+                    .into_par_iter()
+                    .filter_map(|mut child| {
+                        repair(&problem, &mut child);
+                        if problem.validate(&child) {
+                            Some(child)
+                        } else {
+                            None
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                debug!(
+                    "Generation {}: {} valid children out of {}",
+                    generation,
+                    offspring.len(),
+                    children
+                );
+                for child in offspring {
+                    add_individual(&problem, &mut population, child);
+                }
+                population.sort_by_key(|i| i.dislikes);
+                population.truncate(population_size);
+
+                if population[0].dislikes < best_dislikes {
+                    best_dislikes = population[0].dislikes;
+                    info!(
+                        "Generation {}: better pose, dislikes: {}",
+                        generation, best_dislikes
+                    );
+                    pose.replace(population[0].pose.clone());
+                    s.yield_(pose.clone());
+                }
+            }
+
+            s.yield_(pose);
+            done!();
+        })
+    }
+
+    fn seed(&self) -> Option<u64> {
+        Some(seed())
+    }
+}
+
+// Adds the pose to the population if it's valid and not there yet.
+fn add_individual(problem: &Problem, population: &mut Vec<Individual>, pose: Pose) {
+    if pose.vertices.len() != problem.figure.vertices.len() || !problem.validate(&pose) {
+        return;
+    }
+    if population.iter().any(|i| i.pose.vertices == pose.vertices) {
+        return;
+    }
+    let dislikes = problem.dislikes(&pose);
+    population.push(Individual { pose, dislikes });
+}
+
+// Index of the winner of a random tournament, the population is sorted by dislikes.
+fn tournament(population: &[Individual], rng: &mut StdRng) -> usize {
+    (0..TOURNAMENT_SIZE)
+        .map(|_| rng.gen_range(0..population.len()))
+        .min()
+        .unwrap()
+}
+
+// Takes a BFS ball of a random size from `a` and the rest of the vertices from `b`.
+fn crossover(problem: &Problem, a: &Pose, b: &Pose, rng: &mut StdRng) -> Pose {
+    let figure_size = a.vertices.len();
+    // A single vertex has nothing to take from `b`.
+    if figure_size < 2 {
+        return a.clone();
+    }
+    let size = rng.gen_range(1..figure_size);
+    let mut from_a = vec![false; figure_size];
+    let mut queue = VecDeque::new();
+    let start = rng.gen_range(0..figure_size);
+    queue.push_back(start);
+    from_a[start] = true;
+    let mut count = 1;
+    while let Some(v) = queue.pop_front() {
+        for &(_, dst) in &problem.figure.vertex_edges[v] {
+            if count < size && !from_a[dst] {
+                from_a[dst] = true;
+                count += 1;
+                queue.push_back(dst);
+            }
+        }
+    }
+
+    let mut child = b.clone();
+    for (v, &take) in from_a.iter().enumerate() {
+        if take {
+            child.vertices[v] = a.vertices[v];
+        }
+    }
+    child
+}
+
+// Moves the vertices with broken edges to where their edges want them, rounded to the lattice,
+// and then to the nearby lattice points minimizing the edge errors.
+fn repair(problem: &Problem, pose: &mut Pose) {
+    let (mn, mx) = problem.bounding_box();
+    for _ in 0..REPAIR_ROUNDS {
+        let broken = (0..pose.vertices.len())
+            .filter(|&v| {
+                problem.figure.vertex_edges[v]
+                    .iter()
+                    .any(|&(e, _)| problem.figure.test_edge_len2(e, pose) != EdgeTestResult::Ok)
+            })
+            .collect::<Vec<_>>();
+        if broken.is_empty() {
+            return;
+        }
+        for v in broken {
+            let p = relaxed_position(&problem.figure, pose, v);
+            let p = Point {
+                x: p.x.clamp(mn.x, mx.x),
+                y: p.y.clamp(mn.y, mx.y),
+            };
+            pose.vertices[v] = p;
+            let region = (
+                Point {
+                    x: std::cmp::max(mn.x, p.x - REPAIR_RADIUS),
+                    y: std::cmp::max(mn.y, p.y - REPAIR_RADIUS),
+                },
+                Point {
+                    x: std::cmp::min(mx.x, p.x + REPAIR_RADIUS),
+                    y: std::cmp::min(mx.y, p.y + REPAIR_RADIUS),
+                },
+            );
+            pose.center(&problem.figure, v, region);
+        }
+    }
+}
+
+// Average of the positions at the original edge lengths from the neighbours, towards the current
+// one, rounded to the nearest lattice point.
+fn relaxed_position(figure: &Figure, pose: &Pose, v: usize) -> Point {
+    let p = pose.vertices[v];
+    let edges = &figure.vertex_edges[v];
+    if edges.is_empty() {
+        return p;
+    }
+    let (mut x, mut y) = (0.0, 0.0);
+    for &(e, w) in edges {
+        let q = pose.vertices[w];
+        let (dx, dy) = ((p.x - q.x) as f64, (p.y - q.y) as f64);
+        let d = (dx * dx + dy * dy).sqrt();
+        // On top of the neighbour there's no direction to go, stay.
+        let k = if d > 0.0 {
+            figure.edges[e].len2.sqrt() / d
+        } else {
+            1.0
+        };
+        x += q.x as f64 + dx * k;
+        y += q.y as f64 + dy * k;
+    }
+    let n = edges.len() as f64;
+    Point {
+        x: (x / n).round() as i64,
+        y: (y / n).round() as i64,
+    }
+}
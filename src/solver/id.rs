@@ -1,6 +1,7 @@
 use std::{cell::RefCell, rc::Rc};
 
 use crate::problem::{Pose, Problem};
+use crate::storage::Storage;
 
 use super::Solver;
 
@@ -12,6 +13,7 @@ impl Solver for IdSolver {
         &self,
         _problem: Problem,
         pose: Rc<RefCell<Pose>>,
+        _storage: &Storage,
     ) -> generator::LocalGenerator<'a, (), Rc<RefCell<Pose>>> {
         generator::Gn::new_scoped_local(move |mut s| {
             s.yield_(pose);
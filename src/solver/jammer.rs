@@ -4,6 +4,7 @@ use geo::relate::Relate;
 
 use crate::common::*;
 use crate::problem::*;
+use crate::storage::Storage;
 
 use super::Solver;
 
@@ -15,6 +16,7 @@ impl Solver for JammerSolver {
         &self,
         problem: Problem,
         pose: Rc<RefCell<Pose>>,
+        _storage: &Storage,
     ) -> generator::LocalGenerator<'a, (), Rc<RefCell<Pose>>> {
         generator::Gn::new_scoped_local(move |mut s| {
             s.yield_(pose.clone());
@@ -0,0 +1,199 @@
+use rand::rngs::StdRng;
+use rand::Rng;
+use std::collections::VecDeque;
+use std::{cell::RefCell, rc::Rc};
+
+use crate::common::*;
+use crate::problem::*;
+use crate::storage::Storage;
+
+use super::tree_search::{free_placement_order, Precalc, SearchRunner, SearchState};
+use super::{seed, Solver};
+
+// Large neighborhood search: frees a few vertices of a valid pose and re-places them exactly
+// with the tree search while the rest of the pose stays fixed.
+pub struct LnsSolver {
+    // Number of vertices freed on every step.
+    pub neighborhood_size: usize,
+    pub iterations: usize,
+    pub step_timeout: std::time::Duration,
+}
+
+impl Default for LnsSolver {
+    fn default() -> Self {
+        LnsSolver {
+            neighborhood_size: 8,
+            iterations: 500,
+            step_timeout: std::time::Duration::from_secs(1),
+        }
+    }
+}
+
+// Grow the neighborhood after this many steps without an improvement.
+const STALL_ITERATIONS: usize = 50;
+
+#[derive(Clone, Copy, Debug)]
+enum Neighborhood {
+    // BFS ball around a random vertex.
+    Ball,
+    // Vertices nearest to a hole corner that no vertex covers.
+    HoleCorner,
+    // Vertices nearest to a random line through the figure.
+    Cut,
+}
+
+const NEIGHBORHOODS: [Neighborhood; 3] = [
+    Neighborhood::Ball,
+    Neighborhood::HoleCorner,
+    Neighborhood::Cut,
+];
+
+impl Solver for LnsSolver {
+    fn solve_gen<'a>(
+        &self,
+        mut problem: Problem,
+        pose: Rc<RefCell<Pose>>,
+        storage: &Storage,
+    ) -> generator::LocalGenerator<'a, (), Rc<RefCell<Pose>>> {
+        let neighborhood_size = self.neighborhood_size;
+        let iterations = self.iterations;
+        let step_timeout = self.step_timeout;
+        let storage = storage.clone();
+        let mut rng: StdRng = rand::SeedableRng::seed_from_u64(seed());
+
+        generator::Gn::new_scoped_local(move |mut s| {
+            s.yield_(pose.clone());
+
+            let mut current = pose.borrow().clone();
+            if !problem.validate(&current) {
+                match storage.load_pose(&problem.id, None) {
+                    Ok(Some(best)) if problem.validate(&best) => {
+                        info!("Starting from the stored best pose");
+                        current = best;
+                        pose.replace(current.clone());
+                        s.yield_(pose.clone());
+                    }
+                    _ => {
+                        warn!("LNS needs a valid pose to start from");
+                        done!();
+                    }
+                }
+            }
+
+            let figure_size = problem.figure.vertices.len();
+            let precalc = Precalc::new(&mut problem, &mut rng);
+            let mut current_dislikes = problem.dislikes(&current);
+            let mut k = std::cmp::min(neighborhood_size, figure_size);
+            let mut stalled = 0;
+
+            for it in 0..iterations {
+                if current_dislikes == 0 {
+                    break;
+                }
+                let neighborhood = NEIGHBORHOODS[it % NEIGHBORHOODS.len()];
+                let free = select_free(&problem, &current, neighborhood, k, &mut rng);
+                let order = free_placement_order(&problem, &free);
+                debug!("{:?} neighborhood: {:?}", neighborhood, order);
+
+                let mut state = SearchState::new(&problem, &precalc, &order, &current);
+                let mut runner = SearchRunner::new(order, current.clone(), None, &problem, s);
+                runner.best_dislikes = Some(current_dislikes);
+                runner.run(
+                    &problem,
+                    &mut state,
+                    &precalc,
+                    Some(std::time::Instant::now() + step_timeout),
+                );
+                s = runner.scope;
+
+                match runner.best_pose {
+                    Some(better) => {
+                        current_dislikes = problem.dislikes(&better);
+                        info!(
+                            "[{}] {:?} neighborhood of {} improved dislikes to {}",
+                            it, neighborhood, k, current_dislikes
+                        );
+                        current = better;
+                        pose.replace(current.clone());
+                        stalled = 0;
+                    }
+                    None => {
+                        stalled += 1;
+                        if stalled >= STALL_ITERATIONS && k < figure_size {
+                            k += 1;
+                            stalled = 0;
+                            info!("Growing the neighborhood to {}", k);
+                        }
+                    }
+                }
+            }
+
+            s.yield_(pose);
+            done!();
+        })
+    }
+
+    fn seed(&self) -> Option<u64> {
+        Some(seed())
+    }
+}
+
+fn select_free(
+    problem: &Problem,
+    pose: &Pose,
+    neighborhood: Neighborhood,
+    k: usize,
+    rng: &mut StdRng,
+) -> Vec<bool> {
+    let figure_size = pose.vertices.len();
+    let mut free = vec![false; figure_size];
+    match neighborhood {
+        Neighborhood::Ball => {
+            let mut queue = VecDeque::new();
+            let start = rng.gen_range(0..figure_size);
+            queue.push_back(start);
+            free[start] = true;
+            let mut count = 1;
+            while let Some(v) = queue.pop_front() {
+                for &(_, dst) in &problem.figure.vertex_edges[v] {
+                    if count < k && !free[dst] {
+                        free[dst] = true;
+                        count += 1;
+                        queue.push_back(dst);
+                    }
+                }
+            }
+        }
+        Neighborhood::HoleCorner => {
+            let uncovered = problem
+                .hole
+                .iter()
+                .filter(|h| !pose.vertices.contains(h))
+                .collect::<Vec<_>>();
+            if uncovered.is_empty() {
+                return select_free(problem, pose, Neighborhood::Ball, k, rng);
+            }
+            let corner = *uncovered[rng.gen_range(0..uncovered.len())];
+            let mut vertices = (0..figure_size).collect::<Vec<_>>();
+            vertices.sort_by_key(|&v| Figure::distance_squared_int(pose.vertices[v], corner));
+            for &v in vertices.iter().take(k) {
+                free[v] = true;
+            }
+        }
+        Neighborhood::Cut => {
+            let pivot = pose.vertices[rng.gen_range(0..figure_size)];
+            let angle = rng.gen_range(0.0..std::f64::consts::PI);
+            let (dx, dy) = (angle.cos(), angle.sin());
+            let mut vertices = (0..figure_size).collect::<Vec<_>>();
+            vertices.sort_by_key(|&v| {
+                let p = pose.vertices[v];
+                let dist = ((p.x - pivot.x) as f64 * dy - (p.y - pivot.y) as f64 * dx).abs();
+                ordered_float::NotNan::new(dist).unwrap()
+            });
+            for &v in vertices.iter().take(k) {
+                free[v] = true;
+            }
+        }
+    }
+    free
+}
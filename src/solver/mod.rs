@@ -1,44 +1,127 @@
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::Rc,
+};
 
 mod annealing;
+mod beam_search;
 mod cons;
+mod genetic;
 mod id;
 mod jammer;
+mod lns;
 mod tree_search;
 mod wave;
+mod with_bonus;
 
-use crate::{problem::*, storage};
+use crate::common::*;
+use crate::problem::*;
+use crate::storage::Storage;
+
+pub use tree_search::Precalc;
+
+// Seed of the random number generators, so the runs are reproducible.
+pub const SEED: u64 = 42;
+
+thread_local! {
+    // Seed the solvers on this thread use instead of SEED, set by the benchmarks.
+    static SEED_OVERRIDE: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+// Seed for the random number generators of the solvers.
+pub fn seed() -> u64 {
+    SEED_OVERRIDE.with(|s| s.get()).unwrap_or(SEED)
+}
+
+// Runs `f` with the solvers on this thread using the given seed. The generators read it when
+// created and when first resumed, so it must hold for the whole run.
+pub fn with_seed<T>(seed: u64, f: impl FnOnce() -> T) -> T {
+    let previous = SEED_OVERRIDE.with(|s| s.replace(Some(seed)));
+    let result = f();
+    SEED_OVERRIDE.with(|s| s.set(previous));
+    result
+}
 
 pub trait Solver: Sync {
     fn solve_gen<'a>(
         &self,
         problem: Problem,
         pose: Rc<RefCell<Pose>>,
+        storage: &Storage,
     ) -> generator::LocalGenerator<'a, (), Rc<RefCell<Pose>>>;
 
-    fn solve(&self, problem: Problem) -> Solution {
-        let id = problem.id;
-        let initial_pose = problem.figure.get_default_pose();
+    // Seed of the random number generator, None for the deterministic solvers.
+    fn seed(&self) -> Option<u64> {
+        None
+    }
+
+    fn solve(&self, storage: &Storage, problem: Problem, initial_pose: Pose) -> Result<Solution> {
         let pose = self
-            .solve_gen(problem.clone(), Rc::new(RefCell::new(initial_pose)))
+            .solve_gen(
+                problem.clone(),
+                Rc::new(RefCell::new(initial_pose)),
+                storage,
+            )
             .last()
             .unwrap()
             .take();
-        let dislikes = problem.dislikes(&pose);
-        let state = SolutionState {
-            dislikes,
-            valid: problem.validate(&pose),
-            optimal: dislikes == 0 || pose.optimal.unwrap_or_default(),
-        };
-        Solution {
-            id,
-            pose,
-            state,
-            server_state: storage::load_server_state(id).expect("Failed to read server state"),
+        to_solution(storage, &problem, pose)
+    }
+
+    // Like `solve`, but stops once the budget runs out. It's checked between the poses the solver
+    // yields, so it can be overrun. Returns the best valid pose seen, or the last one if none,
+    // and whether the solver finished in time.
+    fn solve_with_budget(
+        &self,
+        storage: &Storage,
+        problem: Problem,
+        initial_pose: Pose,
+        budget: std::time::Duration,
+    ) -> Result<(Solution, bool)> {
+        let start = std::time::Instant::now();
+        let mut gen = self.solve_gen(
+            problem.clone(),
+            Rc::new(RefCell::new(initial_pose.clone())),
+            storage,
+        );
+        let mut last = initial_pose;
+        let mut best: Option<(u64, Pose)> = None;
+        let mut finished = true;
+        while let Some(pose) = gen.resume() {
+            // The solvers can keep working on the yielded pose.
+            last = pose.borrow().clone();
+            if problem.validate(&last) {
+                let dislikes = problem.dislikes(&last);
+                if best.as_ref().map(|(d, _)| dislikes < *d).unwrap_or(true) {
+                    best = Some((dislikes, last.clone()));
+                }
+            }
+            if start.elapsed() > budget {
+                finished = gen.is_done();
+                break;
+            }
         }
+        let pose = best.map(|(_, pose)| pose).unwrap_or(last);
+        Ok((to_solution(storage, &problem, pose)?, finished))
     }
 }
 
+fn to_solution(storage: &Storage, problem: &Problem, pose: Pose) -> Result<Solution> {
+    let dislikes = problem.dislikes(&pose);
+    let state = SolutionState {
+        dislikes,
+        valid: problem.validate(&pose),
+        optimal: dislikes == 0 || pose.optimal.unwrap_or_default(),
+    };
+    Ok(Solution {
+        id: problem.id.clone(),
+        pose,
+        state,
+        server_state: storage.load_server_state(&problem.id)?,
+    })
+}
+
 lazy_static! {
     pub static ref SOLVERS: HashMap<String, Box<dyn Solver>> = {
         use cons::Cons;
@@ -67,6 +150,35 @@ lazy_static! {
         map.insert("tree_search_60min".to_owned(), Box::new(tree_search::TreeSearchSolver{
             timeout: Some(std::time::Duration::from_secs(60 * 60)),
         }));
+        // Beam search over the tree search placement order with 1 minute timeout.
+        map.insert("beam_search".to_owned(), Box::new(beam_search::BeamSearchSolver::default()));
+        // Large neighborhood search over a valid pose.
+        map.insert("lns".to_owned(), Box::new(lns::LnsSolver::default()));
+        // Crossover of the valid poses found by the other solvers.
+        map.insert("genetic".to_owned(), Box::new(genetic::GeneticSolver::default()));
+        // Tree search (10 seconds each) and annealing with a bonus on every edge split for
+        // BREAK_A_LEG, only on the best candidates for the others.
+        for &bonus in &[
+            BonusType::Globalist,
+            BonusType::BreakALeg,
+            BonusType::WallHack,
+            BonusType::SuperFlex,
+        ] {
+            let name = String::from(bonus).to_lowercase();
+            let cap = |n: usize| if bonus == BonusType::BreakALeg { None } else { Some(n) };
+            map.insert(name.clone(), Box::new(with_bonus::WithBonusSolver {
+                bonus,
+                candidates: cap(20),
+                inner: tree_search::TreeSearchSolver {
+                    timeout: Some(std::time::Duration::from_secs(10)),
+                },
+            }));
+            map.insert(format!("{}_annealing", name), Box::new(with_bonus::WithBonusSolver {
+                bonus,
+                candidates: cap(3),
+                inner: annealing::AnnealingSolver::default(),
+            }));
+        }
         map
     };
 }
@@ -7,8 +7,9 @@ use std::{cell::RefCell, rc::Rc};
 
 use crate::common::*;
 use crate::problem::*;
+use crate::storage::Storage;
 
-use super::Solver;
+use super::{seed, Solver};
 
 #[derive(Default)]
 pub struct TreeSearchSolver {
@@ -16,15 +17,22 @@ pub struct TreeSearchSolver {
 }
 
 const ENABLE_POINTS_IN_HOLE: bool = true;
+// How often the search yields its best pose even without improvements, so that the callers
+// can stop it.
+const YIELD_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+// Time for a target bonus search without a timeout, shared by its roots, so that it returns
+// and the later roots get their turn too.
+const TARGET_BONUS_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
 
 impl Solver for TreeSearchSolver {
     fn solve_gen<'a>(
         &self,
         mut problem: Problem,
         pose: Rc<RefCell<Pose>>,
+        _storage: &Storage,
     ) -> generator::LocalGenerator<'a, (), Rc<RefCell<Pose>>> {
         let timeout = self.timeout;
-        let mut rng: StdRng = rand::SeedableRng::seed_from_u64(42);
+        let mut rng: StdRng = rand::SeedableRng::seed_from_u64(seed());
 
         generator::Gn::new_scoped_local(move |mut s| {
             s.yield_(pose.clone());
@@ -34,179 +42,391 @@ impl Solver for TreeSearchSolver {
                 done!();
             }
 
-            let (mn, mx) = problem.bounding_box();
+            let precalc_start = std::time::Instant::now();
+            let precalc = Precalc::new(&mut problem, &mut rng);
+            let precalc_time_taken = std::time::Instant::now() - precalc_start;
+            info!(
+                "Precalc duration: {}.{}s",
+                precalc_time_taken.as_secs(),
+                precalc_time_taken.subsec_millis()
+            );
 
-            let mut start_vertex = 1;
-            // Find min degree vertex.
-            for i in 0..figure_size {
-                // if problem.figure.vertex_edges[i].len()
-                //     < problem.figure.vertex_edges[start_vertex].len()
-                // {
-                //     start_vertex = i;
-                // }
-                if problem.figure.vertex_edges[i].len() == 2 {
-                    start_vertex = i;
+            let result = match problem.target_bonus {
+                None => {
+                    let order = placement_order(&problem);
+                    let mut state = SearchState::new(&problem, &precalc, &order, &pose.borrow());
+                    let mut runner =
+                        SearchRunner::new(order, pose.borrow().clone(), timeout, &problem, s);
+                    runner.run(&problem, &mut state, &precalc, None)
                 }
-            }
-
-            let mut order = Vec::new();
-            let mut v_in_order = vec![0; figure_size];
-            let mut parents = vec![(0, 0); figure_size];
-            let mut topo_vertex_edges = vec![Vec::new(); figure_size];
-            {
-                let mut visited = vec![false; figure_size];
-                topsort(
-                    start_vertex,
-                    None,
-                    None,
-                    &mut order,
-                    &mut visited,
-                    &mut parents,
-                    &problem.figure.vertex_edges,
-                    &mut topo_vertex_edges,
-                );
-                info!("order: {:?}", order);
-                for i in 0..figure_size {
-                    v_in_order[order[i]] = i;
+                // Try every vertex on the bonus position as the root of the search.
+                Some(target) => {
+                    let end = std::time::Instant::now() + timeout.unwrap_or(TARGET_BONUS_TIMEOUT);
+                    let mut best_dislikes = None;
+                    let mut best_pose = None;
+                    for v in 0..figure_size {
+                        // Each root gets its share of the time left, what the earlier roots
+                        // didn't use included.
+                        let now = std::time::Instant::now();
+                        if now >= end {
+                            break;
+                        }
+                        let timeout = (end - now) / (figure_size - v) as u32;
+                        let order = placement_order_from(&problem, v);
+                        let mut state =
+                            SearchState::new(&problem, &precalc, &order, &pose.borrow());
+                        state.fix_vertex(v, target);
+                        let mut runner = SearchRunner::new(
+                            order,
+                            pose.borrow().clone(),
+                            Some(timeout),
+                            &problem,
+                            s,
+                        );
+                        runner.best_dislikes = best_dislikes;
+                        runner.best_pose = best_pose;
+                        runner.run(&problem, &mut state, &precalc, Some(now + timeout));
+                        best_dislikes = runner.best_dislikes;
+                        best_pose = runner.best_pose;
+                        s = runner.scope;
+                        if best_dislikes == Some(0) {
+                            break;
+                        }
+                    }
+                    best_dislikes
+                }
+            };
+            if result.is_some() {
+                if result.unwrap() == 0 {
+                    // TODO: optionally yield pose with optimal = Some(true)
+                    done!();
                 }
             }
 
-            let precalc_start = std::time::Instant::now();
-            problem.precalc();
-            let mut max_delta: usize = 0;
-            for &p1 in &problem.hole {
-                for &p2 in &problem.hole {
-                    max_delta =
-                        std::cmp::max(max_delta, Figure::distance_squared_int(p1, p2) as usize);
-                }
+            // TODO: optionally yield pose with optimal = Some(true)
+            done!();
+        })
+    }
+
+    fn seed(&self) -> Option<u64> {
+        Some(seed())
+    }
+}
+
+// DFS placement order over the whole figure.
+pub fn placement_order(problem: &Problem) -> Vec<usize> {
+    let figure_size = problem.figure.vertices.len();
+    let mut start_vertex = 1;
+    // Find min degree vertex.
+    for i in 0..figure_size {
+        // if problem.figure.vertex_edges[i].len()
+        //     < problem.figure.vertex_edges[start_vertex].len()
+        // {
+        //     start_vertex = i;
+        // }
+        if problem.figure.vertex_edges[i].len() == 2 {
+            start_vertex = i;
+        }
+    }
+    placement_order_from(problem, start_vertex)
+}
+
+// DFS placement order over the whole figure starting from the given vertex.
+pub fn placement_order_from(problem: &Problem, start_vertex: usize) -> Vec<usize> {
+    let figure_size = problem.figure.vertices.len();
+    let mut order = Vec::new();
+    let mut parents = vec![(0, 0); figure_size];
+    let mut topo_vertex_edges = vec![Vec::new(); figure_size];
+    let mut visited = vec![false; figure_size];
+    topsort(
+        start_vertex,
+        None,
+        None,
+        &mut order,
+        &mut visited,
+        &mut parents,
+        &problem.figure.vertex_edges,
+        &mut topo_vertex_edges,
+    );
+    info!("order: {:?}", order);
+    order
+}
+
+// DFS placement order over the `free` vertices only, the rest of the pose stays fixed.
+// Components are started from vertices with the most fixed neighbours.
+pub fn free_placement_order(problem: &Problem, free: &[bool]) -> Vec<usize> {
+    let figure_size = problem.figure.vertices.len();
+    let free_edges = problem
+        .figure
+        .vertex_edges
+        .iter()
+        .map(|edges| {
+            edges
+                .iter()
+                .cloned()
+                .filter(|&(_, dst)| free[dst])
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let mut starts = (0..figure_size).filter(|&v| free[v]).collect::<Vec<_>>();
+    starts.sort_by_key(|&v| {
+        std::cmp::Reverse(
+            problem.figure.vertex_edges[v]
+                .iter()
+                .filter(|&&(_, dst)| !free[dst])
+                .count(),
+        )
+    });
+
+    let mut order = Vec::new();
+    let mut parents = vec![(0, 0); figure_size];
+    let mut topo_vertex_edges = vec![Vec::new(); figure_size];
+    let mut visited = vec![false; figure_size];
+    for v in starts {
+        if visited[v] {
+            continue;
+        }
+        topsort(
+            v,
+            None,
+            None,
+            &mut order,
+            &mut visited,
+            &mut parents,
+            &free_edges,
+            &mut topo_vertex_edges,
+        );
+    }
+    order
+}
+
+// Precalculations shared by all searches over the same problem.
+pub struct Precalc {
+    // Lattice offsets for every squared distance up to the hole diameter.
+    pub delta_precalc: Vec<Vec<(i64, i64)>>,
+    // Allowed squared length range for every edge.
+    pub edge_bounds_precalc: Vec<(i64, i64)>,
+}
+
+impl Precalc {
+    pub fn new(problem: &mut Problem, rng: &mut StdRng) -> Self {
+        problem.precalc();
+        let mut max_delta: usize = 0;
+        for &p1 in &problem.hole {
+            for &p2 in &problem.hole {
+                max_delta = std::cmp::max(max_delta, Figure::distance_squared_int(p1, p2) as usize);
             }
-            info!("Max delta: {}", max_delta);
-            let mut delta_precalc: Vec<Vec<(i64, i64)>> = vec![Vec::new(); max_delta + 1];
-            let delta_sqrt = ((max_delta as f64).sqrt().ceil()) as i64 + 5;
-            for dx in 0..=delta_sqrt {
-                for dy in 0..=delta_sqrt {
-                    let delta = (dx * dx + dy * dy) as usize;
-                    if delta > max_delta {
-                        break;
-                    }
-                    delta_precalc[delta].push((-dx, dy));
-                    delta_precalc[delta].push((-dx, -dy));
-                    delta_precalc[delta].push((dx, dy));
-                    delta_precalc[delta].push((dx, -dy));
+        }
+        // Edges can be longer than the hole, they don't fit then but with WALLHACK.
+        for e in 0..problem.figure.edges.len() {
+            let bounds = problem.figure.edge_len2_bounds_int(e);
+            max_delta = std::cmp::max(max_delta, bounds.1 as usize);
+        }
+        info!("Max delta: {}", max_delta);
+        let mut delta_precalc: Vec<Vec<(i64, i64)>> = vec![Vec::new(); max_delta + 1];
+        let delta_sqrt = ((max_delta as f64).sqrt().ceil()) as i64 + 5;
+        for dx in 0..=delta_sqrt {
+            for dy in 0..=delta_sqrt {
+                let delta = (dx * dx + dy * dy) as usize;
+                if delta > max_delta {
+                    break;
                 }
+                delta_precalc[delta].push((-dx, dy));
+                delta_precalc[delta].push((-dx, -dy));
+                delta_precalc[delta].push((dx, dy));
+                delta_precalc[delta].push((dx, -dy));
             }
-            for v in delta_precalc.iter_mut() {
-                v.sort();
-                v.dedup();
-                v.shuffle(&mut rng);
-            }
+        }
+        for v in delta_precalc.iter_mut() {
+            v.sort();
+            v.dedup();
+            v.shuffle(rng);
+        }
 
-            let mut edge_bounds_precalc: Vec<(i64, i64)> = Vec::new();
-            for edge_index in 0..problem.figure.edges.len() {
+        let mut edge_bounds_precalc: Vec<(i64, i64)> = Vec::new();
+        for edge_index in 0..problem.figure.edges.len() {
+            if problem.superflex == Some(edge_index) {
+                edge_bounds_precalc.push((0, max_delta as i64));
+            } else if problem.globalist {
+                // Any length within the whole budget, the search keeps track of the rest.
+                let len2 = problem.figure.edges[edge_index].len2;
+                let budget = problem.figure.edges.len() as f64 * problem.figure.epsilon;
+                edge_bounds_precalc.push((
+                    ((1.0 - budget) * len2).ceil().max(0.0) as i64,
+                    std::cmp::min(((1.0 + budget) * len2).floor() as i64, max_delta as i64),
+                ));
+            } else {
                 edge_bounds_precalc.push(problem.figure.edge_len2_bounds_int(edge_index));
             }
+        }
 
-            let mut edges_consumed: Vec<i16> = vec![0; figure_size];
-            let mut forward_edges: Vec<Vec<(usize, usize)>> = Vec::new();
-            let mut back_edges: Vec<Vec<(usize, usize)>> = Vec::new();
-            for v in 0..figure_size {
-                forward_edges.push(Vec::new());
-                back_edges.push(Vec::new());
-                for &(e_id, dst) in problem.figure.vertex_edges[v].iter() {
-                    if v_in_order[dst] < v_in_order[v] {
-                        back_edges[v].push((e_id, dst));
-                    } else {
-                        forward_edges[v].push((e_id, dst));
-                    }
-                }
-                info!("{} back edges: {:?}", v, back_edges[v]);
-                info!("{} forward edges: {:?}", v, forward_edges[v]);
-            }
+        Precalc {
+            delta_precalc,
+            edge_bounds_precalc,
+        }
+    }
+}
 
-            let lenx = mx.x - mn.x + 1;
-            let leny = mx.y - mn.y + 1;
-            let mut places_list: Vec<RefCell<Vec<(i64, i64)>>> =
-                vec![RefCell::new(Vec::new()); figure_size];
-            let mut can_place_in: Vec<Vec<Vec<i16>>> =
-                vec![vec![vec![0; leny as usize]; lenx as usize]; figure_size];
+// Placement state for the vertices in `order`, vertices outside of it are fixed in the pose.
+pub struct SearchState {
+    places_list: Vec<RefCell<Vec<(i64, i64)>>>,
+    can_place_in: Vec<Vec<Vec<i16>>>,
+    edges_consumed: Vec<i16>,
+    point_is_on_hole: Vec<Vec<i16>>,
+    covered_points_on_hole: usize,
+    back_edges: Vec<Vec<(usize, usize)>>,
+    forward_edges: Vec<Vec<(usize, usize)>>,
+}
 
-            let mut covered_points_on_hole = 0;
-            let mut point_is_on_hole: Vec<Vec<i16>> = vec![vec![0; leny as usize]; lenx as usize];
+impl SearchState {
+    pub fn new(problem: &Problem, precalc: &Precalc, order: &[usize], pose: &Pose) -> Self {
+        let figure_size = problem.figure.vertices.len();
+        let (mn, mx) = search_box(problem);
 
-            for x in mn.x..=mx.x {
-                for y in mn.y..=mx.y {
-                    let p = Point { x, y };
-                    if !problem.contains_point(&p) {
-                        continue;
-                    }
-                    if problem.point_on_hole(&p) {
-                        point_is_on_hole[(x - mn.x) as usize][(y - mn.y) as usize] = 1;
-                    }
+        let mut v_in_order = vec![None; figure_size];
+        for (i, &v) in order.iter().enumerate() {
+            v_in_order[v] = Some(i);
+        }
 
-                    for v in 0..figure_size {
+        let mut edges_consumed: Vec<i16> = vec![0; figure_size];
+        let mut forward_edges: Vec<Vec<(usize, usize)>> = vec![Vec::new(); figure_size];
+        let mut back_edges: Vec<Vec<(usize, usize)>> = vec![Vec::new(); figure_size];
+        for &v in order {
+            for &(e_id, dst) in problem.figure.vertex_edges[v].iter() {
+                // Fixed vertices count as already placed.
+                match v_in_order[dst] {
+                    Some(i) if i > v_in_order[v].unwrap() => forward_edges[v].push((e_id, dst)),
+                    _ => back_edges[v].push((e_id, dst)),
+                }
+            }
+            info!("{} back edges: {:?}", v, back_edges[v]);
+            info!("{} forward edges: {:?}", v, forward_edges[v]);
+        }
+
+        let lenx = mx.x - mn.x + 1;
+        let leny = mx.y - mn.y + 1;
+        let places_list: Vec<RefCell<Vec<(i64, i64)>>> =
+            vec![RefCell::new(Vec::new()); figure_size];
+        let mut can_place_in: Vec<Vec<Vec<i16>>> =
+            vec![vec![vec![0; leny as usize]; lenx as usize]; figure_size];
+
+        let mut covered_points_on_hole = 0;
+        let mut point_is_on_hole: Vec<Vec<i16>> = vec![vec![0; leny as usize]; lenx as usize];
+
+        for x in mn.x..=mx.x {
+            for y in mn.y..=mx.y {
+                let p = Point { x, y };
+                if !problem.contains_point(&p) {
+                    // The WALLHACK vertex can go anywhere within the bounding box.
+                    if let Some(v) = problem.wallhack.filter(|&v| v_in_order[v].is_some()) {
                         can_place_in[v][(x - mn.x) as usize][(y - mn.y) as usize] += 1;
                     }
+                    continue;
+                }
+                if problem.point_on_hole(&p) {
+                    point_is_on_hole[(x - mn.x) as usize][(y - mn.y) as usize] = 1;
+                }
 
-                    // Do initial placing in coordinates.
-                    // TODO: Can we process them in some clever order?
-                    places_list[start_vertex].borrow_mut().push((x, y));
+                for &v in order {
+                    can_place_in[v][(x - mn.x) as usize][(y - mn.y) as usize] += 1;
                 }
             }
-            // places_list[start_vertex].borrow_mut().push((25, 82));
-
-            // Try to place the starting vertex in one of the hole vertices.
-            // for p in &problem.hole {
-            //     places_list[start_vertex].borrow_mut().push((p.x, p.y));
-            // }
-
-            let precalc_time_taken = std::time::Instant::now() - precalc_start;
-            info!(
-                "Precalc duration: {}.{}s",
-                precalc_time_taken.as_secs(),
-                precalc_time_taken.subsec_millis()
-            );
+        }
 
-            let mut runner = SearchRunner {
-                order,
-                placed: vec![false; figure_size],
-                pose: pose.borrow().clone(),
-                best_dislikes: None,
-                last_log_time: std::time::Instant::now(),
-                timeout,
-                iterations: 0,
-                terminate: false,
-                bbox_mn: mn,
-                bbox_mx: mx,
-                scope: s,
-            };
+        // Propagate the constraints of the fixed vertices.
+        for v in 0..figure_size {
+            if v_in_order[v].is_some() {
+                continue;
+            }
+            let p = pose.vertices[v];
+            if ENABLE_POINTS_IN_HOLE && p.x >= mn.x && p.x <= mx.x && p.y >= mn.y && p.y <= mx.y {
+                let on_hole = &mut point_is_on_hole[(p.x - mn.x) as usize][(p.y - mn.y) as usize];
+                if *on_hole > 0 {
+                    *on_hole += 1;
+                    if *on_hole == 2 {
+                        covered_points_on_hole += 1;
+                    }
+                }
+            }
+            for &(e_id, dst) in problem.figure.vertex_edges[v].iter() {
+                if v_in_order[dst].is_none() {
+                    continue;
+                }
+                edges_consumed[dst] += 1;
+                let bounds = &precalc.edge_bounds_precalc[e_id];
+                for d in bounds.0..=bounds.1 {
+                    for delta in precalc.delta_precalc[d as usize].iter() {
+                        let p_dst = (p.x + delta.0, p.y + delta.1);
+                        if p_dst.0 < mn.x || p_dst.0 > mx.x || p_dst.1 < mn.y || p_dst.1 > mx.y {
+                            continue;
+                        }
+                        can_place_in[dst][(p_dst.0 - mn.x) as usize][(p_dst.1 - mn.y) as usize] +=
+                            1;
+                    }
+                }
+            }
+        }
 
-            let result = runner.place_vertices(
-                0,
-                &problem,
-                &mut places_list,
-                &mut can_place_in,
-                &mut edges_consumed,
-                &mut point_is_on_hole,
-                &mut covered_points_on_hole,
-                &edge_bounds_precalc,
-                &back_edges,
-                &forward_edges,
-                &delta_precalc,
-                None,
-            );
-            if result.is_some() {
-                if result.unwrap() == 0 {
-                    // TODO: optionally yield pose with optimal = Some(true)
-                    done!();
+        // Do initial placing in coordinates for the vertices without placed parents.
+        // TODO: Can we process them in some clever order?
+        for &v in order {
+            if edges_consumed[v] != back_edges[v].len() as i16 {
+                continue;
+            }
+            for x in mn.x..=mx.x {
+                for y in mn.y..=mx.y {
+                    if can_place_in[v][(x - mn.x) as usize][(y - mn.y) as usize]
+                        == 1 + edges_consumed[v]
+                    {
+                        places_list[v].borrow_mut().push((x, y));
+                    }
                 }
             }
+        }
 
-            // TODO: optionally yield pose with optimal = Some(true)
-            done!();
-        })
+        SearchState {
+            places_list,
+            can_place_in,
+            edges_consumed,
+            point_is_on_hole,
+            covered_points_on_hole,
+            back_edges,
+            forward_edges,
+        }
+    }
+
+    // Allows only the given place for a vertex without placed neighbours, like the first one.
+    pub fn fix_vertex(&mut self, v: usize, p: Point) {
+        self.places_list[v]
+            .borrow_mut()
+            .retain(|&(x, y)| x == p.x && y == p.y);
     }
 }
 
+// Area to place the vertices in: the bounding box of the hole, grown by the longest edge of the
+// WALLHACK vertex so that it can go around the hole.
+fn search_box(problem: &Problem) -> (Point, Point) {
+    let (mn, mx) = problem.bounding_box();
+    let margin = match problem.wallhack {
+        Some(v) => problem.figure.vertex_edges[v]
+            .iter()
+            .map(|&(e, _)| (problem.figure.edge_len2_bounds(e).1).sqrt().ceil() as i64)
+            .max()
+            .unwrap_or(0),
+        None => return (mn, mx),
+    };
+    (
+        Point {
+            x: std::cmp::max(mn.x - margin, 0),
+            y: std::cmp::max(mn.y - margin, 0),
+        },
+        Point {
+            x: mx.x + margin,
+            y: mx.y + margin,
+        },
+    )
+}
+
 fn topsort(
     v: usize,
     p: Option<usize>,
@@ -279,23 +499,137 @@ fn find_cycles(
     }
 }
 
-struct SearchRunner<'a> {
+pub struct SearchRunner<'a> {
     // Whether vertex is already placed.
     order: Vec<usize>,
     placed: Vec<bool>,
     // Parent of the vertex in topsort order.
     pose: Pose,
-    best_dislikes: Option<u64>,
+    pub best_dislikes: Option<u64>,
+    pub best_pose: Option<Pose>,
+    // Yielded periodically until a placement is found.
+    start_pose: Pose,
+    // GLOBALIST deformation of the edges between the placed vertices.
+    budget_used: f64,
     last_log_time: std::time::Instant,
+    last_yield_time: std::time::Instant,
     timeout: Option<std::time::Duration>,
     iterations: u64,
     terminate: bool,
     bbox_mn: Point,
     bbox_mx: Point,
-    scope: Scope<'a, (), Rc<RefCell<Pose>>>,
+    pub scope: Scope<'a, (), Rc<RefCell<Pose>>>,
 }
 
 impl<'a> SearchRunner<'a> {
+    pub fn new(
+        order: Vec<usize>,
+        pose: Pose,
+        timeout: Option<std::time::Duration>,
+        problem: &Problem,
+        scope: Scope<'a, (), Rc<RefCell<Pose>>>,
+    ) -> Self {
+        let (mn, mx) = search_box(problem);
+        // The edges between the vertices outside of the order stay as they are in the pose, but
+        // their GLOBALIST deformation still counts.
+        let mut budget_used = 0.0;
+        if problem.globalist {
+            let mut in_order = vec![false; problem.figure.vertices.len()];
+            for &v in &order {
+                in_order[v] = true;
+            }
+            for (e_id, e) in problem.figure.edges.iter().enumerate() {
+                if !in_order[e.v0] && !in_order[e.v1] {
+                    budget_used += problem.figure.edge_deformation(e_id, &pose);
+                }
+            }
+        }
+        SearchRunner {
+            order,
+            placed: vec![false; problem.figure.vertices.len()],
+            start_pose: pose.clone(),
+            pose,
+            best_dislikes: None,
+            best_pose: None,
+            budget_used,
+            last_log_time: std::time::Instant::now(),
+            last_yield_time: std::time::Instant::now(),
+            timeout,
+            iterations: 0,
+            terminate: false,
+            bbox_mn: mn,
+            bbox_mx: mx,
+            scope,
+        }
+    }
+
+    pub fn run(
+        &mut self,
+        problem: &Problem,
+        state: &mut SearchState,
+        precalc: &Precalc,
+        deadline: Option<std::time::Instant>,
+    ) -> Option<u64> {
+        self.place_vertices(
+            0,
+            problem,
+            &mut state.places_list,
+            &mut state.can_place_in,
+            &mut state.edges_consumed,
+            &mut state.point_is_on_hole,
+            &mut state.covered_points_on_hole,
+            &precalc.edge_bounds_precalc,
+            &state.back_edges,
+            &state.forward_edges,
+            &precalc.delta_precalc,
+            deadline,
+        )
+    }
+
+    // Counts a step of the search and, every so often, yields the best pose, logs the speed and
+    // checks the deadline. Steps are both the calls and the placements tried, a single call can go
+    // over a lot of them when the edges have loose bounds.
+    fn out_of_time(&mut self, deadline: Option<std::time::Instant>) -> bool {
+        self.iterations += 1;
+        if self.iterations & 63 == 0 {
+            let log_time = std::time::Instant::now();
+            if deadline.is_some() {
+                if log_time > deadline.unwrap() {
+                    self.terminate = true;
+                    return true;
+                }
+            }
+            if log_time - self.last_yield_time > YIELD_INTERVAL {
+                let pose = self.best_pose.as_ref().unwrap_or(&self.start_pose).clone();
+                self.scope.yield_(Rc::new(RefCell::new(pose)));
+                self.last_yield_time = log_time;
+            }
+            let time_taken = log_time - self.last_log_time;
+            if time_taken > std::time::Duration::from_secs(10) {
+                info!(
+                    "Iterations per second: {}",
+                    (self.iterations as u128 * 1000) / time_taken.as_millis()
+                );
+                self.iterations = 0;
+                self.last_log_time = log_time;
+            }
+        }
+        false
+    }
+
+    // Total deformation of the edges from the vertex to the placed ones.
+    fn edges_deformation(
+        &self,
+        v: usize,
+        problem: &Problem,
+        back_edges: &[Vec<(usize, usize)>],
+    ) -> f64 {
+        back_edges[v]
+            .iter()
+            .map(|&(e_id, _)| problem.figure.edge_deformation(e_id, &self.pose))
+            .sum()
+    }
+
     fn check_back_edges_within_hole(
         &self,
         index: usize,
@@ -330,27 +664,11 @@ impl<'a> SearchRunner<'a> {
             return None;
         }
 
-        self.iterations += 1;
-        if self.iterations >= 50000 {
-            let log_time = std::time::Instant::now();
-            if deadline.is_some() {
-                if log_time > deadline.unwrap() {
-                    self.terminate = true;
-                    return None;
-                }
-            }
-            let time_taken = log_time - self.last_log_time;
-            if time_taken > std::time::Duration::from_secs(10) {
-                info!(
-                    "Iterations per second: {}",
-                    (self.iterations as u128 * 1000) / time_taken.as_millis()
-                );
-                self.iterations = 0;
-                self.last_log_time = log_time;
-            }
+        if self.out_of_time(deadline) {
+            return None;
         }
         debug!("Placing vertex {}", index);
-        if index == problem.figure.vertices.len() {
+        if index == self.order.len() {
             // TODO: Make this incremental.
             if !problem.contains(&self.pose) {
                 return None;
@@ -360,6 +678,7 @@ impl<'a> SearchRunner<'a> {
 
             if self.best_dislikes.unwrap_or(10000000) > dislikes {
                 self.best_dislikes = Some(dislikes);
+                self.best_pose = Some(self.pose.clone());
                 info!("Found better placement, dislikes: {}", dislikes);
                 self.scope.yield_(Rc::new(RefCell::new(self.pose.clone())));
             }
@@ -367,8 +686,14 @@ impl<'a> SearchRunner<'a> {
         }
 
         if ENABLE_POINTS_IN_HOLE {
-            if self.pose.vertices.len() - index + 3 < problem.hole.len() - *covered_points_on_hole {
-                // Can't cover all points on hole!
+            // Every point on hole the remaining vertices can't cover costs at least one dislike.
+            // Over the whole figure only the placements covering nearly all of them are searched,
+            // a partial order has to work with the dislikes of its fixed vertices.
+            let uncovered = (problem.hole.len() - *covered_points_on_hole)
+                .saturating_sub(self.order.len() - index) as u64;
+            let whole_figure = self.order.len() == problem.figure.vertices.len();
+            let slack = if whole_figure { 3 } else { u64::MAX };
+            if uncovered > slack || matches!(self.best_dislikes, Some(d) if uncovered >= d) {
                 return None;
             }
         }
@@ -380,6 +705,9 @@ impl<'a> SearchRunner<'a> {
         let v_places = places_list[v].take();
 
         for p in v_places.iter() {
+            if self.out_of_time(deadline) {
+                break;
+            }
             if index == 0 {
                 info!("Placed vertex {} in ({}, {})", v, p.0, p.1);
             } else {
@@ -388,6 +716,17 @@ impl<'a> SearchRunner<'a> {
 
             self.pose.vertices[v] = Point { x: p.0, y: p.1 };
 
+            let cost = if problem.globalist {
+                self.edges_deformation(v, problem, back_edges)
+            } else {
+                0.0
+            };
+            if self.budget_used + cost > problem.figure.edges.len() as f64 * problem.figure.epsilon
+            {
+                continue;
+            }
+            self.budget_used += cost;
+
             if ENABLE_POINTS_IN_HOLE {
                 if point_is_on_hole[(p.0 - self.bbox_mn.x) as usize]
                     [(p.1 - self.bbox_mn.y) as usize]
@@ -471,13 +810,14 @@ impl<'a> SearchRunner<'a> {
                 // Dive deeper.
                 let child_deadline = match index {
                     0 => match self.timeout {
-                        Some(timeout) => Some(
-                            std::time::Instant::now()
+                        Some(timeout) => {
+                            let place_deadline = std::time::Instant::now()
                                 + std::time::Duration::from_secs_f32(
                                     timeout.as_secs_f32() / v_places.len() as f32,
-                                ),
-                        ),
-                        None => None,
+                                );
+                            Some(deadline.map_or(place_deadline, |d| d.min(place_deadline)))
+                        }
+                        None => deadline,
                     },
                     _ => deadline,
                 };
@@ -502,7 +842,12 @@ impl<'a> SearchRunner<'a> {
                         }
                     }
                 }
-                self.terminate = false;
+                // Only the deadline of a root placement ends, the next one gets a deadline of
+                // its own while the overall one holds.
+                let overall_ended = matches!(deadline, Some(d) if std::time::Instant::now() > d);
+                if index == 0 && self.timeout.is_some() && !overall_ended {
+                    self.terminate = false;
+                }
             }
 
             if ENABLE_POINTS_IN_HOLE {
@@ -548,6 +893,7 @@ impl<'a> SearchRunner<'a> {
                     break;
                 }
             }
+            self.budget_used -= cost;
         }
 
         places_list[v].replace(v_places);
@@ -5,6 +5,7 @@ use geomath::prelude::coordinates::Polar;
 
 use crate::common::*;
 use crate::problem::*;
+use crate::storage::Storage;
 
 use super::Solver;
 
@@ -16,6 +17,7 @@ impl Solver for WaveSolver {
         &self,
         problem: Problem,
         pose: Rc<RefCell<Pose>>,
+        _storage: &Storage,
     ) -> generator::LocalGenerator<'a, (), Rc<RefCell<Pose>>> {
         generator::Gn::new_scoped_local(move |mut s| {
             s.yield_(pose.clone());
@@ -0,0 +1,175 @@
+use std::{cell::RefCell, rc::Rc};
+
+use ordered_float::NotNan;
+
+use crate::bonus::bonus_source;
+use crate::common::*;
+use crate::problem::{BonusType, BonusUse, Figure, Pose, Problem};
+use crate::storage::Storage;
+
+use super::Solver;
+
+// Runs the inner solver with a bonus on the vertices or edges it can apply to, the most promising
+// first, if some problem unlocks it, and keeps the best valid pose.
+pub struct WithBonusSolver<S: Solver> {
+    pub bonus: BonusType,
+    // Tries only that many of the best candidates, all of them if None.
+    pub candidates: Option<usize>,
+    pub inner: S,
+}
+
+impl<S: Solver> Solver for WithBonusSolver<S> {
+    fn solve_gen<'a>(
+        &self,
+        problem: Problem,
+        pose: Rc<RefCell<Pose>>,
+        storage: &Storage,
+    ) -> generator::LocalGenerator<'a, (), Rc<RefCell<Pose>>> {
+        let bonus = self.bonus;
+        let source = match bonus_source(storage, &problem.id, bonus) {
+            Ok(source) => source,
+            Err(e) => {
+                warn!("Failed to find the {} bonus: {}", String::from(bonus), e);
+                None
+            }
+        };
+
+        // Candidate problems with their initial poses, only the split one if the pose already is.
+        let mut variants = Vec::new();
+        let initial = pose.borrow().clone();
+        match source {
+            None => info!(
+                "No problem unlocks {} for problem {}",
+                String::from(bonus),
+                problem.id
+            ),
+            Some(_) if initial.uses(BonusType::BreakALeg) => {
+                let mut split = problem.clone();
+                split.figure = problem.figure_for(&initial).into_owned();
+                variants.push((None, split, initial));
+            }
+            Some(source) => {
+                for idx in candidates(bonus, &problem)
+                    .into_iter()
+                    .take(self.candidates.unwrap_or(usize::MAX))
+                {
+                    let mut variant = problem.clone();
+                    let mut variant_pose = initial.clone();
+                    match bonus {
+                        BonusType::BreakALeg => {
+                            variant.figure = problem.figure.break_a_leg(idx);
+                            variant_pose.break_a_leg(&problem.figure, idx, source.clone());
+                        }
+                        BonusType::WallHack => variant.wallhack = Some(idx),
+                        BonusType::SuperFlex => variant.superflex = Some(idx),
+                        BonusType::Globalist => variant.globalist = true,
+                    }
+                    if bonus != BonusType::BreakALeg {
+                        variant_pose.bonuses = vec![BonusUse {
+                            bonus,
+                            problem: source.clone(),
+                            edge: None,
+                        }];
+                    }
+                    variants.push((Some(idx), variant, variant_pose));
+                }
+            }
+        }
+        let gens = variants
+            .into_iter()
+            .map(|(idx, variant, variant_pose)| {
+                let gen = self.inner.solve_gen(
+                    variant.clone(),
+                    Rc::new(RefCell::new(variant_pose)),
+                    storage,
+                );
+                (idx, variant, gen)
+            })
+            .collect::<Vec<_>>();
+
+        generator::Gn::new_scoped_local(move |mut s| {
+            s.yield_(pose.clone());
+
+            // Every step of the inner solvers is forwarded, the best pose so far if it's not
+            // better, so that the callers can stop the run within their budget.
+            let mut best: Option<(u64, Pose)> = None;
+            for (idx, variant, mut gen) in gens {
+                while let Some(result) = gen.resume() {
+                    let mut result = result.borrow().clone();
+                    if variant.validate(&result) {
+                        // Don't spend the bonus if the pose is fine without it.
+                        if bonus != BonusType::BreakALeg {
+                            let mut plain = result.clone();
+                            plain.bonuses.clear();
+                            if problem.validate(&plain) {
+                                result = plain;
+                            }
+                        }
+                        let dislikes = variant.dislikes(&result);
+                        if best.as_ref().map(|(d, _)| dislikes < *d).unwrap_or(true) {
+                            info!(
+                                "Better pose with {} on {} {:?}: {}",
+                                String::from(bonus),
+                                if bonus == BonusType::WallHack {
+                                    "vertex"
+                                } else {
+                                    "edge"
+                                },
+                                idx,
+                                dislikes
+                            );
+                            best = Some((dislikes, result.clone()));
+                            s.yield_(Rc::new(RefCell::new(result)));
+                            if dislikes == 0 {
+                                done!();
+                            }
+                            continue;
+                        }
+                    }
+                    s.yield_(match &best {
+                        Some((_, best_pose)) => Rc::new(RefCell::new(best_pose.clone())),
+                        None => pose.clone(),
+                    });
+                }
+            }
+            done!();
+        })
+    }
+
+    fn seed(&self) -> Option<u64> {
+        self.inner.seed()
+    }
+}
+
+// Vertices (WALLHACK) or edges (BREAK_A_LEG, SUPERFLEX) to apply the bonus to, the hardest to fit
+// into the hole first: the edges by their length relative to the hole diameter, the ones longer
+// than it can't fit without a bonus at all, and the vertices by their longest edge. The initial
+// pose is often the default one, it says nothing about that. GLOBALIST has a single one.
+fn candidates(bonus: BonusType, problem: &Problem) -> Vec<usize> {
+    let figure = &problem.figure;
+    let mut hole_diameter2 = 0;
+    for (i, &p) in problem.hole.iter().enumerate() {
+        for &q in &problem.hole[i + 1..] {
+            hole_diameter2 = hole_diameter2.max(Figure::distance_squared_int(p, q));
+        }
+    }
+    let relative_len = |e: usize| figure.edges[e].len2 / hole_diameter2.max(1) as f64;
+    let mut scored = match bonus {
+        BonusType::WallHack => (0..figure.vertices.len())
+            .map(|v| {
+                // The longest edge, then the fewer edges the better.
+                let longest = figure.vertex_edges[v]
+                    .iter()
+                    .map(|&(e, _)| relative_len(e))
+                    .fold(0.0, f64::max);
+                (v, longest - figure.vertex_edges[v].len() as f64 / 1000.0)
+            })
+            .collect::<Vec<_>>(),
+        BonusType::BreakALeg | BonusType::SuperFlex => (0..figure.edges.len())
+            .map(|e| (e, relative_len(e)))
+            .collect(),
+        BonusType::Globalist => vec![(0, 0.0)],
+    };
+    scored.sort_by_key(|&(_, score)| std::cmp::Reverse(NotNan::new(score).unwrap()));
+    scored.into_iter().map(|(idx, _)| idx).collect()
+}
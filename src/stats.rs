@@ -0,0 +1,168 @@
+use geo::algorithm::area::Area;
+use rayon::prelude::*;
+use serde_derive::Serialize;
+
+use crate::common::*;
+use crate::problem::ProblemId;
+use crate::storage::Storage;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+    Csv,
+}
+
+impl std::str::FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            _ => Err(anyhow::anyhow!("Unknown stats format '{}'", s)),
+        }
+    }
+}
+
+// A row of the stats, one per problem.
+#[derive(Serialize)]
+pub struct ProblemStats {
+    pub id: ProblemId,
+    pub hole_vertices: usize,
+    pub hole_area: f64,
+    pub bbox_width: i64,
+    pub bbox_height: i64,
+    // Lattice points inside the hole, including its border.
+    pub inside_points: usize,
+    pub figure_vertices: usize,
+    pub figure_edges: usize,
+    pub figure_diameter: f64,
+    pub epsilon: f64,
+    pub max_score: u64,
+    // Bonuses unlocked by the problem, like "GLOBALIST->13".
+    pub bonuses: Vec<String>,
+    // State of the best stored solution, if any.
+    pub dislikes: Option<u64>,
+    pub valid: Option<bool>,
+    pub optimal: Option<bool>,
+    // Solver of the best solution according to the history.
+    pub solver: Option<String>,
+}
+
+// The inside points are counted on the whole bounding box, so the problems go in parallel.
+pub fn collect(storage: &Storage) -> Result<Vec<ProblemStats>> {
+    storage
+        .list_problems()?
+        .into_par_iter()
+        .map(|id| problem_stats(storage, id))
+        .collect()
+}
+
+fn problem_stats(storage: &Storage, id: ProblemId) -> Result<ProblemStats> {
+    let mut problem = storage.load_problem(&id)?;
+    problem.precalc();
+    let (bbox_min, bbox_max) = problem.bounding_box();
+    let solution = storage.load_solution(&id)?;
+    let solver = match &solution {
+        Some(s) => storage
+            .load_history(&id)?
+            .into_iter()
+            .rev()
+            .find(|e| e.pose.vertices == s.pose.vertices)
+            .map(|e| e.solver),
+        None => None,
+    };
+    Ok(ProblemStats {
+        id,
+        hole_vertices: problem.hole.len(),
+        hole_area: problem.poly.unsigned_area(),
+        bbox_width: bbox_max.x - bbox_min.x,
+        bbox_height: bbox_max.y - bbox_min.y,
+        inside_points: problem.inside_points_count(),
+        figure_vertices: problem.figure.vertices.len(),
+        figure_edges: problem.figure.edges.len(),
+        figure_diameter: problem.figure.diameter(),
+        epsilon: problem.figure.epsilon,
+        max_score: problem.score(0, 0),
+        bonuses: problem
+            .bonuses
+            .iter()
+            .map(|b| format!("{}->{}", String::from(b.bonus), b.problem))
+            .collect(),
+        dislikes: solution.as_ref().map(|s| s.state.dislikes),
+        valid: solution.as_ref().map(|s| s.state.valid),
+        optimal: solution.as_ref().map(|s| s.state.optimal),
+        solver,
+    })
+}
+
+pub fn print(stats: &[ProblemStats], format: Format) -> Result<()> {
+    match format {
+        Format::Text => print_text(stats),
+        Format::Json => println!("{}", serde_json::to_string_pretty(stats)?),
+        Format::Csv => print_csv(stats),
+    }
+    Ok(())
+}
+
+fn or<T: ToString>(value: &Option<T>, none: &str) -> String {
+    match value {
+        Some(v) => v.to_string(),
+        None => none.to_owned(),
+    }
+}
+
+fn print_text(stats: &[ProblemStats]) {
+    for s in stats {
+        println!("Problem {}: ", s.id);
+        println!(
+            "  Hole: {} vertices, area={}, bbox={}x{}, inside points={}",
+            s.hole_vertices, s.hole_area, s.bbox_width, s.bbox_height, s.inside_points
+        );
+        println!(
+            "  Figure: {} vertices, {} edges, diameter={:.1}, e={}, max_score={}",
+            s.figure_vertices, s.figure_edges, s.figure_diameter, s.epsilon, s.max_score
+        );
+        if !s.bonuses.is_empty() {
+            println!("  Bonuses: {}", s.bonuses.join(", "));
+        }
+        println!(
+            "  Best: dislikes={}, valid={}, optimal={}, solver={}",
+            or(&s.dislikes, "-"),
+            or(&s.valid, "-"),
+            or(&s.optimal, "-"),
+            or(&s.solver, "-")
+        );
+    }
+}
+
+// The bonuses are joined with spaces, so none of the fields needs quoting.
+fn print_csv(stats: &[ProblemStats]) {
+    println!(
+        "id,hole_vertices,hole_area,bbox_width,bbox_height,inside_points,figure_vertices,\
+         figure_edges,figure_diameter,epsilon,max_score,bonuses,dislikes,valid,optimal,solver"
+    );
+    for s in stats {
+        println!(
+            "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            s.id,
+            s.hole_vertices,
+            s.hole_area,
+            s.bbox_width,
+            s.bbox_height,
+            s.inside_points,
+            s.figure_vertices,
+            s.figure_edges,
+            s.figure_diameter,
+            s.epsilon,
+            s.max_score,
+            s.bonuses.join(" "),
+            or(&s.dislikes, ""),
+            or(&s.valid, ""),
+            or(&s.optimal, ""),
+            or(&s.solver, "")
+        );
+    }
+}
@@ -1,80 +1,490 @@
-use std::path::Path;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use serde_derive::{Deserialize, Serialize};
 
 use crate::common::*;
 use crate::problem::*;
 
-lazy_static! {
-    pub static ref PROBLEMS_PATH: &'static Path = {
-        let path = Path::new("./problems");
-        assert!(path.exists());
-        path
-    };
-    pub static ref SOLUTIONS_PATH: &'static Path = {
-        let path = Path::new("./solutions");
-        assert!(path.exists());
-        path
-    };
+pub const DEFAULT_PROBLEMS_PATH: &str = "./problems";
+pub const DEFAULT_SOLUTIONS_PATH: &str = "./solutions";
+// Subfolder of the solutions with the history of every problem.
+const HISTORY_FOLDER: &str = "history";
+// Subfolder of the solutions with the poses waiting to be submitted and the log of sent ones.
+const OUTBOX_FOLDER: &str = "outbox";
+// Subfolder of the solutions with the reports of the solve runs.
+const REPORTS_FOLDER: &str = "reports";
+// How long to wait before trying to take a busy lock again.
+const LOCK_RETRY: Duration = Duration::from_millis(10);
+// Locks older than this are left by crashed processes, writes never take that long.
+const LOCK_STALE: Duration = Duration::from_secs(30);
+
+// Makes the temporary file names unique between the threads of a process.
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// Contents of a .meta file.
+#[derive(Deserialize, Serialize)]
+struct Meta {
+    #[serde(flatten)]
+    state: SolutionState,
+    // Hash of the .solution file the state is computed for, missing in the older files.
+    #[serde(default)]
+    pose_hash: Option<u64>,
 }
 
-pub fn get_problems_count() -> u32 {
-    PROBLEMS_PATH.read_dir().unwrap().count() as u32
+// Problems and solutions roots, all the files are read and written through it.
+#[derive(Clone, Debug)]
+pub struct Storage {
+    problems_path: PathBuf,
+    solutions_path: PathBuf,
+}
+
+impl Storage {
+    // Both roots must exist, a mistyped path would give an empty workspace otherwise. Only the
+    // subfolders of the solutions are created on demand.
+    pub fn new(problems_path: &Path, solutions_path: &Path) -> Result<Self> {
+        if !problems_path.is_dir() {
+            return Err(anyhow::anyhow!(
+                "Problems folder '{}' does not exist",
+                problems_path.display()
+            ));
+        }
+        if !solutions_path.is_dir() {
+            return Err(anyhow::anyhow!(
+                "Solutions folder '{}' does not exist",
+                solutions_path.display()
+            ));
+        }
+        Ok(Storage {
+            problems_path: problems_path.to_owned(),
+            solutions_path: solutions_path.to_owned(),
+        })
+    }
+
+    pub fn problems_path(&self) -> &Path {
+        &self.problems_path
+    }
+
+    // Folder with the solutions of a single solver, created on demand.
+    pub fn solver_solutions_path(&self, name: &str) -> Result<PathBuf> {
+        let path = self.solutions_path.join(name);
+        std::fs::create_dir_all(&path)?;
+        Ok(path)
+    }
+
+    // Ids of the problems from the ID.problem file names, the numbers first and possibly with
+    // gaps. Other files are ignored, and so are the names the id doesn't map back to, like
+    // 007.problem for problem 7.
+    pub fn list_problems(&self) -> Result<Vec<ProblemId>> {
+        let mut ids = Vec::new();
+        for entry in self.problems_path.read_dir()? {
+            let path = entry?.path();
+            if !path.is_file() || path.extension().map(|e| e != "problem").unwrap_or(true) {
+                continue;
+            }
+            let stem = path.file_stem().and_then(|s| s.to_str());
+            match stem.and_then(|s| s.parse::<ProblemId>().ok()) {
+                Some(id) if Some(&id.to_string()[..]) == stem => ids.push(id),
+                Some(id) => warn!(
+                    "Skipping '{}', problem {} is read from '{}.problem'",
+                    path.display(),
+                    id,
+                    id
+                ),
+                None => warn!("Skipping '{}', not a valid problem id", path.display()),
+            }
+        }
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    // Names of the per-solver solution folders.
+    pub fn list_subfolders(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in self.solutions_path.read_dir()? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            if let Some(name) = entry.file_name().to_str() {
+                if name != HISTORY_FOLDER && name != OUTBOX_FOLDER && name != REPORTS_FOLDER {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    pub fn load_problem(&self, id: &ProblemId) -> Result<Problem> {
+        Problem::from_json(id.clone(), self.problem_json(id)?.as_bytes())
+    }
+
+    pub fn save_problem_json(&self, id: &ProblemId, data: &str) -> Result<()> {
+        write_atomic(
+            &self.problems_path.join(format!("{}.problem", id)),
+            data.as_bytes(),
+        )
+    }
+
+    // Problem file contents as downloaded from the portal.
+    pub fn problem_json(&self, id: &ProblemId) -> Result<String> {
+        Ok(std::fs::read_to_string(
+            self.problems_path.join(format!("{}.problem", id)),
+        )?)
+    }
+
+    pub fn load_solution(&self, id: &ProblemId) -> Result<Option<Solution>> {
+        let path = self.solutions_path.join(format!("{}.solution", id));
+        let state_path = self.solutions_path.join(format!("{}.meta", id));
+        if !path.exists() || !state_path.exists() {
+            return Ok(None);
+        }
+        let data = std::fs::read(path)?;
+        let pose = Pose::from_json(&data)?;
+        let meta: Meta = serde_json::from_slice(&std::fs::read(state_path)?)?;
+        let state = match meta.pose_hash {
+            Some(hash) if hash != pose_hash(&data) => {
+                warn!("Metadata of solution {} is out of date, recomputing", id);
+                self.compute_state(id, &pose)?
+            }
+            _ => meta.state,
+        };
+        Ok(Some(Solution {
+            id: id.clone(),
+            pose,
+            state,
+            server_state: self.load_server_state(id)?,
+        }))
+    }
+
+    // State of a pose without its metadata, a proof of optimality is lost with it.
+    fn compute_state(&self, id: &ProblemId, pose: &Pose) -> Result<SolutionState> {
+        let mut problem = self.load_problem(id)?;
+        problem.figure = problem.figure_for(pose).into_owned();
+        if pose.vertices.len() != problem.figure.vertices.len() {
+            return Ok(SolutionState {
+                dislikes: u64::MAX,
+                valid: false,
+                optimal: false,
+            });
+        }
+        let dislikes = problem.dislikes(pose);
+        let valid = problem.validate(pose);
+        Ok(SolutionState {
+            dislikes,
+            valid,
+            optimal: valid && dislikes == 0,
+        })
+    }
+
+    pub fn save_solution(&self, solution: &Solution, subfolder: Option<&str>) -> Result<()> {
+        match subfolder {
+            Some(_) => self.write_solution(&self.folder(subfolder), solution),
+            None => {
+                let _lock = self.lock_best(&solution.id)?;
+                self.write_solution(&self.solutions_path, solution)
+            }
+        }
+    }
+
+    // Replaces the best solution if the new one has fewer dislikes, returns the dislikes of the
+    // replaced one. The best is re-read under the lock, so concurrent runs never make it worse.
+    pub fn save_best_if_better(&self, solution: &Solution) -> Result<Option<u64>> {
+        let _lock = self.lock_best(&solution.id)?;
+        let best_dislikes = self
+            .load_solution(&solution.id)?
+            .filter(|s| s.state.valid)
+            .map(|s| s.state.dislikes)
+            .unwrap_or(u64::MAX);
+        if best_dislikes <= solution.state.dislikes {
+            return Ok(None);
+        }
+        self.write_solution(&self.solutions_path, solution)?;
+        Ok(Some(best_dislikes))
+    }
+
+    // Every file is replaced atomically, but the pair is not: the pose goes first, and the
+    // metadata keeps its hash, so a reader between the two recomputes the metadata.
+    fn write_solution(&self, solutions_path: &Path, solution: &Solution) -> Result<()> {
+        let data = solution.pose.to_json()?;
+        write_atomic(
+            &solutions_path.join(format!("{}.solution", solution.id)),
+            data.as_bytes(),
+        )?;
+        write_meta(
+            &solutions_path.join(format!("{}.meta", solution.id)),
+            &solution.state,
+            data.as_bytes(),
+        )
+    }
+
+    // Lock on the best solution of a problem, shared by the threads and the processes.
+    fn lock_best(&self, id: &ProblemId) -> Result<Lock> {
+        Lock::acquire(self.solutions_path.join(format!("{}.lock", id)))
+    }
+
+    pub fn load_pose(&self, id: &ProblemId, subfolder: Option<&str>) -> Result<Option<Pose>> {
+        let path = self.folder(subfolder).join(format!("{}.solution", id));
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(Pose::from_json(&std::fs::read(path)?)?))
+    }
+
+    pub fn load_state(
+        &self,
+        id: &ProblemId,
+        subfolder: Option<&str>,
+    ) -> Result<Option<SolutionState>> {
+        let path = self.folder(subfolder).join(format!("{}.meta", id));
+        if !path.exists() {
+            return Ok(None);
+        }
+        let meta: Meta = serde_json::from_slice(&std::fs::read(path)?)?;
+        Ok(Some(meta.state))
+    }
+
+    // Replaces only the metadata of a stored pose.
+    pub fn save_state(
+        &self,
+        id: &ProblemId,
+        subfolder: Option<&str>,
+        state: &SolutionState,
+    ) -> Result<()> {
+        let _lock = match subfolder {
+            Some(_) => None,
+            None => Some(self.lock_best(id)?),
+        };
+        let folder = self.folder(subfolder);
+        let data = std::fs::read(folder.join(format!("{}.solution", id)))?;
+        write_meta(&folder.join(format!("{}.meta", id)), state, &data)
+    }
+
+    fn folder(&self, subfolder: Option<&str>) -> PathBuf {
+        match subfolder {
+            Some(s) => self.solutions_path.join(s),
+            None => self.solutions_path.to_owned(),
+        }
+    }
+
+    // Appends an entry to the history of the problem, one JSON entry per line.
+    pub fn append_history(&self, id: &ProblemId, entry: &HistoryEntry) -> Result<()> {
+        let path = self
+            .solver_solutions_path(HISTORY_FOLDER)?
+            .join(format!("{}.history", id));
+        append_line(&path, &entry.to_json()?)
+    }
+
+    // All the history entries of the problem, oldest first.
+    pub fn load_history(&self, id: &ProblemId) -> Result<Vec<HistoryEntry>> {
+        let path = self
+            .solutions_path
+            .join(HISTORY_FOLDER)
+            .join(format!("{}.history", id));
+        if !path.exists() {
+            return Ok(vec![]);
+        }
+        std::fs::read_to_string(path)?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| HistoryEntry::from_json(line.as_bytes()))
+            .collect()
+    }
+
+    // Puts a pose into the outbox, replacing the one queued for the same problem.
+    pub fn queue_submission(&self, entry: &OutboxEntry) -> Result<()> {
+        let path = self
+            .solver_solutions_path(OUTBOX_FOLDER)?
+            .join(format!("{}.outbox", entry.id));
+        write_atomic(&path, entry.to_json()?.as_bytes())
+    }
+
+    // Poses waiting to be submitted, by problem id.
+    pub fn load_outbox(&self) -> Result<Vec<OutboxEntry>> {
+        let path = self.solutions_path.join(OUTBOX_FOLDER);
+        if !path.exists() {
+            return Ok(vec![]);
+        }
+        let mut entries = Vec::new();
+        for entry in path.read_dir()? {
+            let path = entry?.path();
+            if path.extension().map(|e| e == "outbox").unwrap_or(false) {
+                entries.push(OutboxEntry::from_json(&std::fs::read(path)?)?);
+            }
+        }
+        entries.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(entries)
+    }
+
+    // Takes a sent pose out of the outbox and appends it to the log of the sent ones.
+    pub fn mark_sent(&self, entry: &OutboxEntry) -> Result<()> {
+        self.take_from_outbox(entry, "sent.log")
+    }
+
+    // Takes a pose the portal refused for good out of the outbox, into the log of the failed ones.
+    pub fn mark_failed(&self, entry: &OutboxEntry) -> Result<()> {
+        self.take_from_outbox(entry, "failed.log")
+    }
+
+    fn take_from_outbox(&self, entry: &OutboxEntry, log: &str) -> Result<()> {
+        let outbox_path = self.solver_solutions_path(OUTBOX_FOLDER)?;
+        append_line(&outbox_path.join(log), &entry.to_json()?)?;
+        let path = outbox_path.join(format!("{}.outbox", entry.id));
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    // Writes the report of a solve run, named after its start time and the process, so that the
+    // concurrent runs don't overwrite each other. Returns the path.
+    pub fn save_run_report(&self, started_at: u64, data: &str) -> Result<PathBuf> {
+        let name = format!("{}-{}.json", started_at, std::process::id());
+        let path = self.solver_solutions_path(REPORTS_FOLDER)?.join(name);
+        write_atomic(&path, data.as_bytes())?;
+        Ok(path)
+    }
+
+    pub fn load_server_state(&self, id: &ProblemId) -> Result<ServerState> {
+        let server_state_path = self.solutions_path.join(format!("{}.state", id));
+        if server_state_path.exists() {
+            ServerState::from_json(&std::fs::read(server_state_path)?)
+        } else {
+            Ok(ServerState::new())
+        }
+    }
+
+    pub fn save_server_state(&self, id: &ProblemId, state: &ServerState) -> Result<()> {
+        write_atomic(
+            &self.solutions_path.join(format!("{}.state", id)),
+            state.to_json()?.as_bytes(),
+        )
+    }
 }
 
-pub fn load_problem(id: u32) -> Result<Problem> {
-    Problem::from_json(
-        id,
-        &std::fs::read(PROBLEMS_PATH.join(format!("{}.problem", id)))?,
-    )
+// A single write, so the lines from the concurrent writers don't interleave.
+fn append_line(path: &Path, line: &str) -> Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    file.write_all(format!("{}\n", line).as_bytes())?;
+    Ok(())
 }
 
-pub fn load_solution(id: u32) -> Result<Option<Solution>> {
-    let path = SOLUTIONS_PATH.join(format!("{}.solution", id));
-    let state_path = SOLUTIONS_PATH.join(format!("{}.meta", id));
-    if !path.exists() || !state_path.exists() {
-        return Ok(None);
-    }
-    Ok(Some(Solution {
-        id,
-        pose: Pose::from_json(&std::fs::read(path)?)?,
-        state: SolutionState::from_json(&std::fs::read(state_path)?)?,
-        server_state: load_server_state(id)?,
-    }))
+// Writes to a temporary file next to the target and renames it over, so readers never see
+// a partially written file.
+fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    if path.file_name().is_none() {
+        return Err(anyhow::anyhow!("'{}' is not a file path", path.display()));
+    }
+    let tmp_path = unique_path(path, "tmp");
+    let result = (|| -> Result<()> {
+        let mut file = std::fs::File::create(&tmp_path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    result
 }
 
-pub fn save_solution(solution: &Solution, subfolder: Option<&str>) -> Result<()> {
-    let solutions_path = match subfolder {
-        Some(s) => SOLUTIONS_PATH.join(s),
-        None => SOLUTIONS_PATH.to_owned(),
+fn write_meta(path: &Path, state: &SolutionState, pose_data: &[u8]) -> Result<()> {
+    let meta = Meta {
+        state: state.clone(),
+        pose_hash: Some(pose_hash(pose_data)),
     };
-    std::fs::write(
-        &solutions_path.join(format!("{}.solution", solution.id)),
-        solution.pose.to_json()?,
-    )?;
-    std::fs::write(
-        solutions_path.join(format!("{}.meta", solution.id)),
-        solution.state.to_json()?,
-    )?;
-    Ok(())
+    write_atomic(path, serde_json::to_string(&meta)?.as_bytes())
 }
 
-pub fn load_custom_solution(path: &Path) -> Result<Pose> {
-    Ok(Pose::from_json(&std::fs::read(path)?)?)
+// FNV-1a of the pose file, stable between the builds unlike the std hashers.
+fn pose_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
 }
 
-pub fn load_server_state(id: u32) -> Result<ServerState> {
-    let server_state_path = SOLUTIONS_PATH.join(format!("{}.state", id));
-    if server_state_path.exists() {
-        ServerState::from_json(&std::fs::read(
-            SOLUTIONS_PATH.join(format!("{}.state", id)),
-        )?)
-    } else {
-        Ok(ServerState::new())
+// Hidden file name next to the path, unique between the threads and the processes.
+fn unique_path(path: &Path, extension: &str) -> PathBuf {
+    path.with_file_name(format!(
+        ".{}.{}.{}.{}",
+        path.file_name().unwrap_or_default().to_string_lossy(),
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed),
+        extension
+    ))
+}
+
+// Exclusive lock file, removed when dropped.
+struct Lock {
+    path: PathBuf,
+}
+
+impl Lock {
+    fn acquire(path: PathBuf) -> Result<Self> {
+        loop {
+            match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(_) => return Ok(Lock { path }),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    if is_stale(&path) {
+                        remove_stale(&path)?;
+                    } else {
+                        std::thread::sleep(LOCK_RETRY);
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
     }
 }
 
-pub fn save_server_state(id: u32, state: &ServerState) -> Result<()> {
-    Ok(std::fs::write(
-        SOLUTIONS_PATH.join(format!("{}.state", id)),
-        state.to_json()?,
-    )?)
+fn is_stale(path: &Path) -> bool {
+    std::fs::metadata(path)
+        .and_then(|m| m.modified())
+        .map(|t| t.elapsed().map(|e| e > LOCK_STALE).unwrap_or(false))
+        .unwrap_or(false)
+}
+
+// Moves the lock aside under a unique name first, so that of the waiters seeing it stale only
+// one gets it. If another waiter has taken the lock over in between, the fresh one goes back,
+// unless a third one has taken the free place already: then two processes hold it and the moved
+// one is left aside for inspection.
+fn remove_stale(path: &Path) -> Result<()> {
+    let aside = unique_path(path, "stale");
+    if std::fs::rename(path, &aside).is_err() {
+        return Ok(());
+    }
+    if is_stale(&aside) {
+        warn!("Removed stale lock '{}'", path.display());
+    } else if let Err(e) = std::fs::hard_link(&aside, path) {
+        return Err(anyhow::anyhow!(
+            "Lost lock '{}' while taking it over, moved to '{}': {}",
+            path.display(),
+            aside.display(),
+            e
+        ));
+    }
+    // A stale lock goes away, a linked back one keeps its place.
+    let _ = std::fs::remove_file(&aside);
+    Ok(())
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+pub fn load_custom_solution(path: &Path) -> Result<Pose> {
+    Ok(Pose::from_json(&std::fs::read(path)?)?)
 }
@@ -0,0 +1,465 @@
+use std::time::{Duration, Instant};
+
+use crate::common::*;
+use crate::portal::{is_refused, Session, SolutionStatus};
+use crate::problem::{OutboxEntry, ProblemId, ServerState, Verdict};
+use crate::storage::Storage;
+
+pub struct UploadOptions {
+    // Only print what would be uploaded.
+    pub dry_run: bool,
+    // Problems to consider, all of them if None.
+    pub ids: Option<Vec<ProblemId>>,
+    // Upload only if the dislikes go down by at least that much.
+    pub min_improvement: u64,
+    // Upload the valid solutions even if they are not better than on the server.
+    pub force: bool,
+    // Minimal time between the submissions, the portal throttles them.
+    pub interval: Duration,
+}
+
+impl Default for UploadOptions {
+    fn default() -> Self {
+        UploadOptions {
+            dry_run: false,
+            ids: None,
+            min_improvement: 1,
+            force: false,
+            interval: Duration::from_secs(5),
+        }
+    }
+}
+
+// A line of the summary table.
+struct Row {
+    id: ProblemId,
+    dislikes: Option<u64>,
+    server_dislikes: u64,
+    result: String,
+}
+
+// Result of a flush for a problem: its id, the dislikes of the queued pose and what happened.
+pub type FlushResult = (ProblemId, u64, String);
+
+// Rounds of attempts to drain the outbox with the `flush` command, the delay between them
+// doubles every time.
+pub const FLUSH_ROUNDS: u32 = 4;
+#[cfg(not(test))]
+const FLUSH_BACKOFF: Duration = Duration::from_secs(5);
+#[cfg(test)]
+const FLUSH_BACKOFF: Duration = Duration::from_millis(1);
+
+// Queues the solutions worth uploading and makes a single attempt to send them, the failed
+// ones stay in the outbox for `flush`.
+pub fn upload_all(storage: &Storage, session: &Session, options: &UploadOptions) -> Result<()> {
+    let ids = match &options.ids {
+        Some(ids) => ids.clone(),
+        None => storage.list_problems()?,
+    };
+    let mut rows = Vec::new();
+    for i in ids {
+        let solution = storage.load_solution(&i)?;
+        if let Some(mut s) = solution {
+            // The verdict on the previous submission could have been late.
+            if !options.dry_run && s.server_state.verdict == Some(Verdict::Pending) {
+                if let Some(pose_id) = s.server_state.submission_id.clone() {
+                    match session.solution_status(&i, &pose_id) {
+                        Ok(status) => {
+                            record_status(&i, &mut s.server_state, &status);
+                            storage.save_server_state(&i, &s.server_state)?;
+                        }
+                        Err(e) => warn!("Failed to get the verdict for problem {}: {}", i, e),
+                    }
+                }
+            }
+
+            let mut row = Row {
+                id: i.clone(),
+                dislikes: Some(s.state.dislikes),
+                server_dislikes: s.server_state.dislikes,
+                result: String::new(),
+            };
+            if !s.state.valid {
+                warn!("For problem {} solution does not fit into the hole", i);
+                row.result = "skipped, invalid".to_owned();
+                rows.push(row);
+                continue;
+            }
+            let improvement = s.server_state.dislikes.saturating_sub(s.state.dislikes);
+            if !options.force && improvement < options.min_improvement {
+                info!(
+                    "For problem {} solution with score {} is not enough of an improvement (server has {})",
+                    i, s.state.dislikes, s.server_state.dislikes
+                );
+                row.result = "skipped, no improvement".to_owned();
+                rows.push(row);
+                continue;
+            }
+            if options.dry_run {
+                row.result = "would upload".to_owned();
+                rows.push(row);
+                continue;
+            }
+
+            storage.queue_submission(&OutboxEntry::new(&s))?;
+            row.result = "queued".to_owned();
+            rows.push(row);
+        } else {
+            info!("No solution for problem {}", i);
+            rows.push(Row {
+                id: i,
+                dislikes: None,
+                server_dislikes: u64::MAX,
+                result: "no solution".to_owned(),
+            });
+        }
+    }
+
+    if !options.dry_run {
+        let flushed = flush(
+            storage,
+            session,
+            options.interval,
+            1,
+            options.ids.as_deref(),
+        )?;
+        for (id, dislikes, result) in flushed {
+            let server_dislikes = storage.load_server_state(&id)?.dislikes;
+            match rows.iter_mut().find(|r| r.id == id) {
+                Some(row) => {
+                    row.server_dislikes = server_dislikes;
+                    row.result = result;
+                }
+                // Left in the outbox by the earlier uploads.
+                None => rows.push(Row {
+                    id,
+                    dislikes: Some(dislikes),
+                    server_dislikes,
+                    result,
+                }),
+            }
+        }
+        rows.sort_by(|a, b| a.id.cmp(&b.id));
+    }
+    print_summary(&rows, options.dry_run);
+    Ok(())
+}
+
+// Submits the poses from the outbox in up to `rounds` attempts, the failed ones stay there
+// unless the portal refuses them for good. Only the poses for `ids` if given, the others are
+// left for later.
+pub fn flush(
+    storage: &Storage,
+    session: &Session,
+    interval: Duration,
+    rounds: u32,
+    ids: Option<&[ProblemId]>,
+) -> Result<Vec<FlushResult>> {
+    let load_outbox = || -> Result<Vec<OutboxEntry>> {
+        let mut entries = storage.load_outbox()?;
+        if let Some(ids) = ids {
+            entries.retain(|e| ids.contains(&e.id));
+        }
+        Ok(entries)
+    };
+    let mut results = Vec::new();
+    let mut backoff = FLUSH_BACKOFF;
+    let mut last_upload: Option<Instant> = None;
+    for round in 0..rounds {
+        let queue = load_outbox()?;
+        if queue.is_empty() {
+            break;
+        }
+        if round > 0 {
+            warn!(
+                "{} submissions failed, retrying in {}s",
+                queue.len(),
+                backoff.as_secs()
+            );
+            std::thread::sleep(backoff);
+            backoff *= 2;
+        }
+        let available = session.hello();
+        for mut entry in queue {
+            let result = match &available {
+                Ok(()) => {
+                    if let Some(last) = last_upload {
+                        if let Some(wait) = interval.checked_sub(last.elapsed()) {
+                            std::thread::sleep(wait);
+                        }
+                    }
+                    last_upload = Some(Instant::now());
+                    submit(storage, session, &mut entry)
+                }
+                Err(e) => Err(anyhow::anyhow!("Portal is not available: {}", e)),
+            };
+            match result {
+                Ok(result) => {
+                    storage.mark_sent(&entry)?;
+                    results.push((entry.id.clone(), entry.dislikes, result));
+                }
+                Err(e) if is_refused(&e) => {
+                    warn!(
+                        "The portal refused the solution for problem {}: {}",
+                        entry.id, e
+                    );
+                    entry.attempts += 1;
+                    entry.last_error = Some(e.to_string());
+                    storage.mark_failed(&entry)?;
+                    results.push((entry.id.clone(), entry.dislikes, format!("dropped, {}", e)));
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to submit the solution for problem {}: {}",
+                        entry.id, e
+                    );
+                    entry.attempts += 1;
+                    entry.last_error = Some(e.to_string());
+                    storage.queue_submission(&entry)?;
+                }
+            }
+        }
+    }
+    for entry in load_outbox()? {
+        results.push((
+            entry.id,
+            entry.dislikes,
+            format!(
+                "queued after {} attempts, {}",
+                entry.attempts,
+                entry.last_error.unwrap_or_default()
+            ),
+        ));
+    }
+    results.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(results)
+}
+
+pub fn print_flush_results(storage: &Storage, results: &[FlushResult]) -> Result<()> {
+    let rows = results
+        .iter()
+        .map(|(id, dislikes, result)| -> Result<Row> {
+            Ok(Row {
+                id: id.clone(),
+                dislikes: Some(*dislikes),
+                server_dislikes: storage.load_server_state(id)?.dislikes,
+                result: result.clone(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    print_summary(&rows, false);
+    Ok(())
+}
+
+// Uploads a pose and waits for the verdict. Only the upload itself can fail, so that a sent pose
+// is never queued again.
+fn submit(storage: &Storage, session: &Session, entry: &mut OutboxEntry) -> Result<String> {
+    let id = entry.id.clone();
+    warn!(
+        "Uploading solution for problem {}, dislikes: {}",
+        id, entry.dislikes
+    );
+    let pose_id = session.upload_solution(&id, &entry.pose)?;
+    entry.pose_id = Some(pose_id.clone());
+    entry.sent_at = Some(unix_time());
+
+    let mut server_state = match storage.load_server_state(&id) {
+        Ok(server_state) => server_state,
+        Err(e) => {
+            warn!("Failed to load the server state of problem {}: {}", id, e);
+            ServerState::new()
+        }
+    };
+    server_state.submission_id = Some(pose_id.clone());
+    server_state.submitted_at = entry.sent_at;
+    server_state.submitted_dislikes = Some(entry.dislikes);
+    server_state.verdict = Some(Verdict::Pending);
+    let result = match session.wait_for_status(&id, &pose_id) {
+        Ok(status) => {
+            record_status(&id, &mut server_state, &status);
+            match status.state {
+                Verdict::Valid => format!("uploaded, server has {}", server_state.dislikes),
+                Verdict::Invalid => "uploaded, rejected".to_owned(),
+                Verdict::Pending => "uploaded, pending".to_owned(),
+            }
+        }
+        Err(e) => {
+            warn!("Failed to get the verdict for problem {}: {}", id, e);
+            "uploaded, pending".to_owned()
+        }
+    };
+    if let Err(e) = storage.save_server_state(&id, &server_state) {
+        warn!("Failed to save the server state of problem {}: {}", id, e);
+    }
+    Ok(result)
+}
+
+fn print_summary(rows: &[Row], dry_run: bool) {
+    let dislikes = |d: Option<u64>| match d {
+        Some(d) if d != u64::MAX => d.to_string(),
+        _ => "-".to_owned(),
+    };
+    println!("{:>7}  {:>8}  {:>8}  Result", "Problem", "Local", "Server");
+    for row in rows {
+        println!(
+            "{:>7}  {:>8}  {:>8}  {}",
+            row.id,
+            dislikes(row.dislikes),
+            dislikes(Some(row.server_dislikes)),
+            row.result
+        );
+    }
+    let uploaded = rows
+        .iter()
+        .filter(|r| r.result.starts_with("uploaded") || r.result == "would upload")
+        .count();
+    println!(
+        "{} of {} problems {}",
+        uploaded,
+        rows.len(),
+        if dry_run { "to upload" } else { "uploaded" }
+    );
+}
+
+// Parses a list of problem ids like "1,5,10-20,spiral", the ranges are only for the numbers.
+pub fn parse_ids(s: &str) -> Result<Vec<ProblemId>> {
+    let mut ids = Vec::new();
+    for part in s.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+        let range = part.split_once('-').and_then(|(from, to)| {
+            Some((from.trim().parse::<u32>().ok()?, to.trim().parse().ok()?))
+        });
+        match range {
+            Some((from, to)) => ids.extend((from..=to).map(ProblemId::from)),
+            None => ids.push(part.parse()?),
+        }
+    }
+    Ok(ids)
+}
+
+// Applies the portal verdict on the last submission, warns if it disagrees with ours.
+fn record_status(id: &ProblemId, server_state: &mut ServerState, status: &SolutionStatus) {
+    server_state.verdict = Some(status.state);
+    match status.state {
+        Verdict::Valid => {
+            let dislikes = status.dislikes.unwrap_or(u64::MAX);
+            if let Some(ours) = server_state.submitted_dislikes.filter(|&d| d != dislikes) {
+                warn!(
+                    "For problem {} the portal has {} dislikes, we had {}",
+                    id, dislikes, ours
+                );
+            }
+            // A forced upload of a worse pose doesn't replace the best one on the server.
+            server_state.dislikes = server_state.dislikes.min(dislikes);
+        }
+        Verdict::Invalid => warn!(
+            "For problem {} the portal rejected the solution we consider valid: {}",
+            id,
+            status.error.as_deref().unwrap_or("no reason given")
+        ),
+        Verdict::Pending => warn!(
+            "For problem {} the submission is still pending, it will be checked on the next upload",
+            id
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_portal::{self, MockPortal};
+    use crate::problem::Pose;
+
+    // A pose with no dislikes for problem 13.
+    const VALID_POSE: &str = r#"{"vertices": [[0, 20], [20, 0], [20, 40], [40, 20]]}"#;
+
+    fn entry(id: u32, pose: &str) -> OutboxEntry {
+        OutboxEntry {
+            id: id.into(),
+            dislikes: 0,
+            queued_at: 0,
+            attempts: 0,
+            last_error: None,
+            pose_id: None,
+            sent_at: None,
+            pose: Pose::from_json(pose.as_bytes()).unwrap(),
+        }
+    }
+
+    #[test]
+    fn flush_retries_transient_failures() {
+        let (storage, _dir) = mock_portal::storage();
+        let portal = MockPortal::start(storage.clone()).unwrap();
+        storage.queue_submission(&entry(13, VALID_POSE)).unwrap();
+
+        portal.fail_next("POST", &[503]);
+        let results = flush(&storage, &portal.session(), Duration::ZERO, 1, None).unwrap();
+        assert_eq!(results.len(), 1);
+        assert!(results[0].2.starts_with("queued after 1 attempts"));
+        let queued = storage.load_outbox().unwrap();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].attempts, 1);
+
+        // Fails again, and goes through in the next round.
+        portal.fail_next("POST", &[503]);
+        let results = flush(&storage, &portal.session(), Duration::ZERO, 2, None).unwrap();
+        assert_eq!(
+            results,
+            vec![(13.into(), 0, "uploaded, server has 0".to_owned())]
+        );
+        assert!(storage.load_outbox().unwrap().is_empty());
+        let server_state = storage.load_server_state(&13.into()).unwrap();
+        assert_eq!(server_state.dislikes, 0);
+        assert_eq!(server_state.verdict, Some(Verdict::Valid));
+    }
+
+    #[test]
+    fn flush_drops_refused_poses() {
+        let (storage, _dir) = mock_portal::storage();
+        let portal = MockPortal::start(storage.clone()).unwrap();
+        storage.queue_submission(&entry(13, VALID_POSE)).unwrap();
+
+        portal.fail_next("POST", &[400]);
+        let results = flush(&storage, &portal.session(), Duration::ZERO, 3, None).unwrap();
+        assert_eq!(results.len(), 1);
+        assert!(results[0].2.starts_with("dropped"));
+        assert!(storage.load_outbox().unwrap().is_empty());
+        // The hello and a single upload.
+        assert_eq!(portal.requests(), 2);
+        assert_eq!(storage.load_server_state(&13.into()).unwrap().verdict, None);
+    }
+
+    #[test]
+    fn flush_only_the_selected_problems() {
+        let (storage, _dir) = mock_portal::storage();
+        let portal = MockPortal::start(storage.clone()).unwrap();
+        storage.queue_submission(&entry(13, VALID_POSE)).unwrap();
+        storage.queue_submission(&entry(4, VALID_POSE)).unwrap();
+
+        let ids = [ProblemId::from(13)];
+        let results = flush(&storage, &portal.session(), Duration::ZERO, 1, Some(&ids)).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0, ids[0]);
+        let queued = storage.load_outbox().unwrap();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].id, ProblemId::from(4));
+    }
+
+    #[test]
+    fn record_status_keeps_the_best_accepted_dislikes() {
+        let id = ProblemId::from(13);
+        let mut server_state = ServerState::new();
+        let valid = |dislikes| SolutionStatus {
+            state: Verdict::Valid,
+            dislikes: Some(dislikes),
+            error: None,
+        };
+        record_status(&id, &mut server_state, &valid(120));
+        assert_eq!(server_state.dislikes, 120);
+        // A forced upload of a worse pose.
+        record_status(&id, &mut server_state, &valid(150));
+        assert_eq!(server_state.dislikes, 120);
+        assert_eq!(server_state.verdict, Some(Verdict::Valid));
+        record_status(&id, &mut server_state, &valid(80));
+        assert_eq!(server_state.dislikes, 80);
+    }
+}